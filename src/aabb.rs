@@ -0,0 +1,109 @@
+use cgmath::{EuclideanSpace, Matrix4, Point3, Transform};
+
+use crate::{ray::Ray, P3, V3};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: P3,
+    pub max: P3,
+}
+
+impl Aabb {
+    pub fn new(min: P3, max: P3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_half_size(half_size: V3) -> Self {
+        Self::new(P3::from_vec(-half_size), P3::from_vec(half_size))
+    }
+
+    pub fn centroid(&self) -> P3 {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn size(&self) -> V3 {
+        self.max - self.min
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self::new(
+            Point3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            Point3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        )
+    }
+
+    pub fn intersection(&self, other: &Aabb) -> Self {
+        Self::new(
+            Point3::new(
+                self.min.x.max(other.min.x),
+                self.min.y.max(other.min.y),
+                self.min.z.max(other.min.z),
+            ),
+            Point3::new(
+                self.max.x.min(other.max.x),
+                self.max.y.min(other.max.y),
+                self.max.z.min(other.max.z),
+            ),
+        )
+    }
+
+    pub fn grow(&self, amount: f64) -> Self {
+        let amount = V3::new(amount, amount, amount);
+        Self::new(self.min - amount, self.max + amount)
+    }
+
+    pub fn translated(&self, offset: V3) -> Self {
+        Self::new(self.min + offset, self.max + offset)
+    }
+
+    /// Bounding box of this box after transformation by `mat`, computed from its 8 corners.
+    pub fn transformed(&self, mat: &Matrix4<f64>) -> Self {
+        let corners = (0..8).map(|i| {
+            Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        });
+        corners
+            .map(|p| mat.transform_point(p))
+            .map(|p| Self::new(p, p))
+            .fold(None, |acc: Option<Aabb>, b| {
+                Some(acc.map_or(b, |a| a.union(&b)))
+            })
+            .unwrap()
+    }
+
+    /// Slab test; returns whether the ray enters the box within `tmin..tmax`.
+    pub fn hit(&self, ray: &Ray, mut tmin: f64, mut tmax: f64) -> bool {
+        let pos = ray.pos();
+        let dir = ray.dir();
+        for axis in 0..3 {
+            let inv_d = 1.0 / dir[axis];
+            let mut t0 = (self.min[axis] - pos[axis]) * inv_d;
+            let mut t1 = (self.max[axis] - pos[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // NaN-safe: comparisons with NaN keep the previous bound
+            if t0 > tmin {
+                tmin = t0;
+            }
+            if t1 < tmax {
+                tmax = t1;
+            }
+            if tmax < tmin {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,189 @@
+use std::cmp::Ordering;
+
+use crate::{
+    aabb::Aabb,
+    ray::Ray,
+    traits::{Bounded, HitRecord, Hittable},
+};
+
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+enum Node {
+    Leaf {
+        bbox: Aabb,
+        start: usize,
+        end: usize,
+    },
+    Branch {
+        bbox: Aabb,
+        right: usize,
+    },
+}
+
+impl Node {
+    fn bbox(&self) -> &Aabb {
+        match self {
+            Self::Leaf { bbox, .. } | Self::Branch { bbox, .. } => bbox,
+        }
+    }
+}
+
+/// Bounding volume hierarchy over a list of objects.
+///
+/// Objects without a bounding box (ie. infinite planes) are kept aside and tested linearly.
+/// The left child of a branch node is always stored right after it, so only the index of the
+/// right child needs to be recorded.
+#[derive(Clone, Debug)]
+pub struct Bvh<T> {
+    objects: Vec<T>,
+    nodes: Vec<Node>,
+    unbounded: Vec<T>,
+}
+
+impl<T: Bounded> Bvh<T> {
+    pub fn new(objects: Vec<T>) -> Self {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .map(|o| (o.bounding_box(), o))
+            .partition(|(bbox, _)| bbox.is_some());
+        let mut items: Vec<_> = bounded
+            .into_iter()
+            .map(|(bbox, o)| (bbox.unwrap(), o))
+            .collect();
+        let mut nodes = Vec::with_capacity(2 * items.len() / MAX_LEAF_SIZE + 1);
+        if !items.is_empty() {
+            build(&mut nodes, &mut items, 0);
+        }
+        Self {
+            objects: items.into_iter().map(|(_, o)| o).collect(),
+            nodes,
+            unbounded: unbounded.into_iter().map(|(_, o)| o).collect(),
+        }
+    }
+}
+
+fn build<T>(nodes: &mut Vec<Node>, items: &mut [(Aabb, T)], offset: usize) -> usize {
+    let bbox = items
+        .iter()
+        .skip(1)
+        .fold(items[0].0, |acc, (b, _)| acc.union(b));
+    let idx = nodes.len();
+    if items.len() <= MAX_LEAF_SIZE {
+        nodes.push(Node::Leaf {
+            bbox,
+            start: offset,
+            end: offset + items.len(),
+        });
+        return idx;
+    }
+
+    let centroids = items.iter().skip(1).map(|(b, _)| b.centroid()).fold(
+        Aabb::new(items[0].0.centroid(), items[0].0.centroid()),
+        |acc, c| acc.union(&Aabb::new(c, c)),
+    );
+    let size = centroids.size();
+    let axis = if size.x > size.y && size.x > size.z {
+        0
+    } else if size.y > size.z {
+        1
+    } else {
+        2
+    };
+    let mid = items.len() / 2;
+    items.select_nth_unstable_by(mid, |(a, _), (b, _)| {
+        a.centroid()[axis]
+            .partial_cmp(&b.centroid()[axis])
+            .unwrap_or(Ordering::Equal)
+    });
+
+    nodes.push(Node::Branch { bbox, right: 0 });
+    let (left, right) = items.split_at_mut(mid);
+    build(nodes, left, offset);
+    let right_idx = build(nodes, right, offset + mid);
+    nodes[idx] = Node::Branch {
+        bbox,
+        right: right_idx,
+    };
+    idx
+}
+
+impl<T> Bvh<T> {
+    /// Iterate over all the objects, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.objects.iter().chain(self.unbounded.iter())
+    }
+
+    /// Closest-hit traversal with a custom per-object intersection function, for primitives
+    /// which do not produce a full `HitRecord` on their own. `t` extracts the hit distance.
+    pub fn closest_hit<'a, H, F, D>(
+        &'a self,
+        ray: &Ray,
+        tmin: f64,
+        tmax: f64,
+        hit: F,
+        t: D,
+    ) -> Option<H>
+    where
+        F: Fn(&'a T, f64, f64) -> Option<H>,
+        D: Fn(&H) -> f64,
+    {
+        let mut closest = None;
+        let mut tmax = tmax;
+        for obj in &self.unbounded {
+            if let Some(h) = hit(obj, tmin, tmax).filter(|h| t(h).is_finite()) {
+                tmax = t(&h);
+                closest = Some(h);
+            }
+        }
+        if self.nodes.is_empty() {
+            return closest;
+        }
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            if !node.bbox().hit(ray, tmin, tmax) {
+                continue;
+            }
+            match *node {
+                Node::Leaf { start, end, .. } => {
+                    for obj in &self.objects[start..end] {
+                        if let Some(h) = hit(obj, tmin, tmax).filter(|h| t(h).is_finite()) {
+                            tmax = t(&h);
+                            closest = Some(h);
+                        }
+                    }
+                }
+                Node::Branch { right, .. } => {
+                    stack.push(right);
+                    stack.push(idx + 1);
+                }
+            }
+        }
+        closest
+    }
+}
+
+impl<T: Hittable> Hittable for Bvh<T> {
+    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord<'_>> {
+        self.closest_hit(
+            ray,
+            tmin,
+            tmax,
+            |obj, tmin, tmax| obj.hit(ray, tmin, tmax),
+            |h| h.t,
+        )
+    }
+}
+
+impl<T> Bounded for Bvh<T> {
+    fn bounding_box(&self) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            None
+        } else {
+            self.nodes.first().map(|n| *n.bbox())
+        }
+    }
+}
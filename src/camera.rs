@@ -1,8 +1,8 @@
 use cgmath::{EuclideanSpace, InnerSpace};
 
 use crate::ray::Ray;
+use crate::utils::SampleRng;
 use crate::{P3, V3, config};
-use rand::prelude::ThreadRng;
 
 
 #[derive(Clone, Debug)]
@@ -42,12 +42,13 @@ impl From<Camera> for config::Camera {
         // vertical / focus_dist / 2.0 = tan(theta/2) * v;
         let tanv: crate::V3 = c.vertical / (focus_dist * 2.0);
         let ttheta_over_2 = tanv.magnitude();
-        let fov = ttheta_over_2.atan().to_degrees();
+        let fov = 2.0 * ttheta_over_2.atan().to_degrees();
 
         Self {
             pos: c.origin.into(),
-            look_at: (c.w * focus_dist).into(),
-            up: c.u.into(),
+            // w points backwards, from the focus plane to the camera
+            look_at: (c.origin - c.w * focus_dist).into(),
+            up: c.v.into(),
             focus_distance: Some(focus_dist),
             fov,
             aperture: c.lens_radius * 2.0,
@@ -96,7 +97,7 @@ impl Camera {
             lens_radius,
         }
     }
-    pub fn get_ray(&self, rng: &mut ThreadRng, s: f64, t: f64) -> Ray {
+    pub fn get_ray(&self, rng: &mut SampleRng, s: f64, t: f64) -> Ray {
         let rd: V3 = self.lens_radius * crate::utils::random_in_unit_disk(rng);
         let offset: V3 = self.u * rd.x + self.v * rd.y;
         Ray::new(
@@ -105,3 +106,29 @@ impl Camera {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sample_rng;
+
+    #[test]
+    fn config_round_trip() {
+        let config = config::Camera {
+            pos: [1.0, 2.0, 3.0],
+            look_at: [-2.0, 0.5, -1.0],
+            up: [0.1, 1.0, 0.0],
+            focus_distance: Some(4.0),
+            aperture: 0.2,
+            fov: 50.0,
+        };
+        let camera = Camera::from_config(config, 1.5);
+        let again = Camera::from_config(config::Camera::from(camera.clone()), 1.5);
+        for &(s, t) in &[(0.0, 0.0), (0.5, 0.5), (1.0, 0.25)] {
+            let a = camera.get_ray(&mut sample_rng(0, 0, 0, 0), s, t);
+            let b = again.get_ray(&mut sample_rng(0, 0, 0, 0), s, t);
+            assert!((a.pos() - b.pos()).magnitude() < 1e-9, "{:?} {:?}", a, b);
+            assert!((a.dir() - b.dir()).magnitude() < 1e-9, "{:?} {:?}", a, b);
+        }
+    }
+}
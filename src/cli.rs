@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+
+use serde_yaml::Value;
+
+use crate::{loader::SceneFormat, output::Format};
+
+pub const USAGE: &str =
+    "Usage: raytracer [OPTIONS] <SCENE>\n       raytracer dump [OPTIONS] <SCENE>";
+
+pub const HELP: &str = "\
+Render a YAML scene file.
+
+Usage: raytracer [OPTIONS] <SCENE>
+       raytracer dump [OPTIONS] <SCENE>
+
+Commands:
+  dump                     Write the scene to the output file (.yml or .json) or to stdout,
+                           with includes, names and default values resolved
+
+Options:
+  -o, --output <FILE>      Output image (.ppm, .png, .exr or .pfm); PPM on stdout if omitted
+      --width <PIXELS>     Image width [default: 800]
+      --height <PIXELS>    Image height [default: 9/16 of the width]
+  -s, --samples <N>        Samples per pixel, overriding the scene file
+  -b, --bounces <N>        Maximum number of bounces, overriding the scene file
+      --seed <N>           Random seed, overriding the scene file
+  -j, --threads <N>        Number of render threads [default: one per CPU]
+      --set <KEY=VALUE>    Override any value of the scene file, e.g. `camera.fov=40` or
+                           `world[2].material.ior=1.33`; VALUE is parsed as YAML
+      --region <X,Y,W,H>   Only render this rectangle of pixels, counted from the top left
+      --crop               Write only the rendered region instead of the full image
+      --spectral           Trace each sample at a single wavelength, for dispersion
+      --bit-depth <BITS>   Bit depth of PNG output, 8 or 16 [default: 8]
+      --sample-map <FILE>  Also write a heat map of the number of samples per pixel
+      --denoise            Denoise the image, if the scene file doesn't already
+      --check              Only check the scene file for errors, without rendering
+  -q, --quiet              Don't show progress
+  -h, --help               Print this help
+";
+
+/// Command-line arguments.
+#[derive(Clone, Debug)]
+pub struct Args {
+    pub scene: PathBuf,
+    pub output: Option<PathBuf>,
+    pub width: u32,
+    pub height: u32,
+    pub threads: Option<usize>,
+    /// Scene file values to replace, as paths into the document and their new values
+    pub overrides: Vec<(String, Value)>,
+    pub bit_depth: u8,
+    pub sample_map: Option<PathBuf>,
+    pub denoise: bool,
+    /// Validate the scene without rendering it
+    pub check: bool,
+    pub quiet: bool,
+    pub help: bool,
+    /// Write the scene back out instead of rendering it
+    pub dump: bool,
+}
+
+impl Args {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut scene = None;
+        let mut output = None;
+        let (mut width, mut height) = (None, None);
+        let mut threads = None;
+        let mut overrides = vec![];
+        let mut bit_depth = 8;
+        let mut sample_map = None;
+        let (mut denoise, mut check, mut quiet, mut help) = (false, false, false, false);
+
+        let mut args = args.into_iter().peekable();
+        let dump = args.next_if(|a| a == "dump").is_some();
+        while let Some(arg) = args.next() {
+            // Accept both `--option value` and `--option=value`
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if arg.starts_with("--") => (name.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline
+                    .map(str::to_string)
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("missing value for `{}`", name))
+            };
+            match name.as_str() {
+                "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+                "--width" => width = Some(positive(&name, &value()?)?),
+                "--height" => height = Some(positive(&name, &value()?)?),
+                "-s" | "--samples" => {
+                    let samples: u32 = positive(&name, &value()?)?;
+                    overrides.push(("samples".to_string(), samples.into()));
+                }
+                "-b" | "--bounces" => {
+                    let bounces: u32 = number(&name, &value()?)?;
+                    overrides.push(("bounces".to_string(), bounces.into()));
+                }
+                "--seed" => {
+                    let seed: u64 = number(&name, &value()?)?;
+                    overrides.push(("seed".to_string(), seed.into()));
+                }
+                "-j" | "--threads" => threads = Some(positive(&name, &value()?)?),
+                "--set" => {
+                    let value = value()?;
+                    let (key, value) = value.split_once('=').ok_or_else(|| {
+                        format!("expected KEY=VALUE for `--set`, got `{}`", value)
+                    })?;
+                    let value = serde_yaml::from_str(value)
+                        .map_err(|e| format!("invalid value for `{}`: {}", key, e))?;
+                    overrides.push((key.to_string(), value));
+                }
+                "--region" => {
+                    let value = value()?;
+                    let numbers = value
+                        .split(',')
+                        .map(|n| number::<u32>(&name, n.trim()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let region = match numbers[..] {
+                        [x, y, width, height] => format!(
+                            "{{x: {}, y: {}, width: {}, height: {}}}",
+                            x, y, width, height
+                        ),
+                        _ => {
+                            return Err(format!("expected X,Y,W,H for `--region`, got `{}`", value))
+                        }
+                    };
+                    overrides.push(("region".to_string(), serde_yaml::from_str(&region).unwrap()));
+                }
+                "--crop" => overrides.push(("crop".to_string(), true.into())),
+                "--spectral" => overrides.push(("spectral".to_string(), true.into())),
+                "--bit-depth" => {
+                    bit_depth = match value()?.as_str() {
+                        "8" => 8,
+                        "16" => 16,
+                        v => return Err(format!("`--bit-depth` must be 8 or 16, got `{}`", v)),
+                    }
+                }
+                "--sample-map" => sample_map = Some(PathBuf::from(value()?)),
+                "--denoise" => denoise = true,
+                "--check" => check = true,
+                "-q" | "--quiet" => quiet = true,
+                "-h" | "--help" => help = true,
+                _ if arg.starts_with('-') && arg != "-" => {
+                    return Err(format!("unknown option `{}`", arg))
+                }
+                _ if scene.is_none() => scene = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument `{}`", arg)),
+            }
+        }
+
+        if let Some(path) = output.as_ref().filter(|_| dump) {
+            if SceneFormat::from_path(path).is_none() {
+                return Err(format!(
+                    "unknown scene format for `{}`, expected .yml or .json",
+                    path.display()
+                ));
+            }
+        }
+        for path in output.iter().filter(|_| !dump).chain(&sample_map) {
+            if Format::from_path(path).is_none() {
+                return Err(format!(
+                    "unknown image format for `{}`, expected .ppm, .png, .exr or .pfm",
+                    path.display()
+                ));
+            }
+        }
+        let width = width.unwrap_or(800);
+        Ok(Self {
+            scene: match scene {
+                Some(scene) => scene,
+                None if help => PathBuf::new(),
+                None => return Err("missing scene file".to_string()),
+            },
+            output,
+            width,
+            height: height.unwrap_or_else(|| ((width as f64 * 9.0 / 16.0) as u32).max(1)),
+            threads,
+            overrides,
+            bit_depth,
+            sample_map,
+            denoise,
+            check,
+            quiet,
+            help,
+            dump,
+        })
+    }
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number `{}` for `{}`", value, name))
+}
+
+fn positive<T: std::str::FromStr + Default + PartialOrd>(
+    name: &str,
+    value: &str,
+) -> Result<T, String> {
+    let n = number(name, value)?;
+    if n > T::default() {
+        Ok(n)
+    } else {
+        Err(format!("`{}` must be greater than 0", name))
+    }
+}
+
+/// Replace the value found at `path` in `doc`, such as `camera.fov` or `world[2].radius`.
+/// Missing keys of mappings are created, but sequence indices must exist.
+pub fn set_value(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    let mut node = doc;
+    for segment in path.split('.') {
+        let (key, indices) = match segment.find('[') {
+            Some(i) => segment.split_at(i),
+            None => (segment, ""),
+        };
+        if !key.is_empty() {
+            if node.is_null() {
+                *node = Value::Mapping(Default::default());
+            }
+            let map = node
+                .as_mapping_mut()
+                .ok_or_else(|| format!("cannot set `{}`: `{}` is not in a mapping", path, key))?;
+            let key = Value::String(key.to_string());
+            if !map.contains_key(&key) {
+                map.insert(key.clone(), Value::Null);
+            }
+            node = map.get_mut(&key).unwrap();
+        }
+        for index in indices.split_terminator(']') {
+            let index: usize = index
+                .strip_prefix('[')
+                .and_then(|i| i.parse().ok())
+                .ok_or_else(|| format!("cannot set `{}`: invalid index in `{}`", path, segment))?;
+            node = node
+                .as_sequence_mut()
+                .and_then(|s| s.get_mut(index))
+                .ok_or_else(|| {
+                    format!("cannot set `{}`: no element {} in `{}`", path, index, key)
+                })?;
+        }
+    }
+    *node = value;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn options() {
+        let args = parse(&[
+            "-s",
+            "16",
+            "--width=320",
+            "scene.yml",
+            "--set",
+            "camera.fov=40",
+        ])
+        .unwrap();
+        assert_eq!(args.scene, PathBuf::from("scene.yml"));
+        assert_eq!((args.width, args.height), (320, 180));
+        assert!(!args.dump);
+        assert_eq!(
+            args.overrides,
+            vec![
+                ("samples".to_string(), Value::from(16)),
+                ("camera.fov".to_string(), Value::from(40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["scene.yml", "--samples", "0"]).is_err());
+        assert!(parse(&["scene.yml", "--height"]).is_err());
+        assert!(parse(&["scene.yml", "--bit-depth", "12"]).is_err());
+        assert!(parse(&["scene.yml", "-o", "image.jpg"]).is_err());
+        assert!(parse(&["scene.yml", "other.yml"]).is_err());
+        assert!(parse(&["scene.yml", "--set", "samples"]).is_err());
+        assert!(parse(&["scene.yml", "--region", "1,2,3"]).is_err());
+        assert!(parse(&["dump", "scene.yml", "-o", "scene.png"]).is_err());
+        assert!(parse(&["scene.yml", "-o", "scene.json"]).is_err());
+        assert!(
+            parse(&["dump", "scene.yml", "-o", "scene.json"])
+                .unwrap()
+                .dump
+        );
+    }
+
+    #[test]
+    fn set_values() {
+        let mut doc: Value =
+            serde_yaml::from_str("samples: 10\nworld:\n  - {radius: 1}\n  - {radius: 2}").unwrap();
+        set_value(&mut doc, "samples", 20.into()).unwrap();
+        set_value(&mut doc, "world[1].radius", 3.into()).unwrap();
+        set_value(&mut doc, "camera.fov", 40.into()).unwrap();
+        let expected: Value = serde_yaml::from_str(
+            "samples: 20\nworld:\n  - {radius: 1}\n  - {radius: 3}\ncamera: {fov: 40}",
+        )
+        .unwrap();
+        assert_eq!(doc, expected);
+        assert!(set_value(&mut doc, "world[2].radius", 3.into()).is_err());
+        assert!(set_value(&mut doc, "samples.x", 3.into()).is_err());
+    }
+}
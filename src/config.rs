@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 type V3 = [f64; 3];
 
@@ -24,11 +24,58 @@ const fn default_fov() -> f64 {
     60.0
 }
 
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Wrap {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Nearest,
+    #[default]
+    Bilinear,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ColorInput {
-    Color { color: V3 },
-    Texture { filename: PathBuf },
+    Color {
+        color: V3,
+    },
+    Texture {
+        filename: PathBuf,
+        #[serde(default)]
+        wrap: Wrap,
+        #[serde(default)]
+        filter: Filter,
+    },
+    Checker {
+        even: V3,
+        odd: V3,
+        #[serde(default = "default_texture_scale")]
+        scale: f64,
+    },
+    Noise {
+        low: V3,
+        high: V3,
+        #[serde(default = "default_texture_scale")]
+        scale: f64,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+    },
+}
+
+const fn default_texture_scale() -> f64 {
+    1.0
+}
+
+const fn default_octaves() -> u32 {
+    4
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -46,11 +93,116 @@ pub enum Material {
         fuzz: f64,
     },
     Dielectric {
+        /// Fraction of the light left after travelling one unit inside the material
         albedo: ColorInput,
-        ior: f64,
+        ior: Ior,
+        #[serde(default)]
+        roughness: f64,
+    },
+    Emissive {
+        color: ColorInput,
+        #[serde(default = "default_strength")]
+        strength: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base: Option<Box<Material>>,
+    },
+    /// Physically based surface: a GGX microfacet specular layer over a diffuse base, which
+    /// becomes a metal tinted by `base_color` as `metallic` goes to 1.
+    Principled {
+        base_color: ColorInput,
+        #[serde(default)]
+        metallic: f64,
+        #[serde(default = "default_roughness")]
+        roughness: f64,
+        /// Reflectance of the dielectric layer at normal incidence, scaled so that 0.5 gives
+        /// the 4% of common materials
+        #[serde(default = "default_specular")]
+        specular: f64,
+    },
+    /// Boundary of a closed object filled with a participating medium, which light crosses
+    /// without bending.
+    Volume {
+        #[serde(default)]
+        absorption: V3,
+        #[serde(default)]
+        scattering: V3,
+        #[serde(default)]
+        anisotropy: f64,
+    },
+    /// Blend of two materials, which is `b` where `factor` is 1
+    Mix {
+        a: Box<Material>,
+        b: Box<Material>,
+        factor: Factor,
+    },
+    /// Clear varnish over a base material, reflecting more at grazing angles
+    Coated {
+        base: Box<Material>,
+        #[serde(default = "default_coat_ior")]
+        coat_ior: f64,
+        #[serde(default)]
+        coat_roughness: f64,
     },
 }
 
+/// Value between 0 and 1, either constant or read from the average of the channels of a
+/// texture.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Factor {
+    Constant(f64),
+    Texture(ColorInput),
+}
+
+const fn default_coat_ior() -> f64 {
+    1.5
+}
+
+/// Index of refraction, either constant or varying with the wavelength in micrometers. Outside
+/// of spectral rendering, the index at the sodium D line (587.6 nm) is used.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Ior {
+    Constant(f64),
+    /// n = a + b / λ²
+    Cauchy {
+        a: f64,
+        b: f64,
+    },
+    /// n² = 1 + Σ b λ² / (λ² - c)
+    Sellmeier {
+        b: [f64; 3],
+        c: [f64; 3],
+    },
+}
+
+impl Ior {
+    /// Index at `wavelength` in nanometers.
+    pub fn at(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength * 1e-3).powi(2);
+        match self {
+            Self::Constant(n) => *n,
+            Self::Cauchy { a, b } => a + b / l2,
+            Self::Sellmeier { b, c } => {
+                let sum: f64 = b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+const fn default_roughness() -> f64 {
+    0.5
+}
+
+const fn default_specular() -> f64 {
+    0.5
+}
+
+const fn default_strength() -> f64 {
+    1.0
+}
+
 const fn default_fuzz() -> f64 {
     0.01
 }
@@ -90,33 +242,440 @@ pub enum SDF {
     },
 }
 
+/// Rotation, either as Euler angles in degrees (applied around X, then Y, then Z), or as an
+/// angle in degrees around an axis.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Rotation {
+    Euler(V3),
+    AxisAngle { axis: V3, angle: f64 },
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self::Euler([0.0; 3])
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Scale {
+    Uniform(f64),
+    NonUniform(V3),
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self::Uniform(1.0)
+    }
+}
+
+/// Object-to-world transform, either as a raw 4x4 matrix given row by row, or as components
+/// applied in the order scale, rotate, translate.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Transform {
+    Matrix {
+        matrix: [[f64; 4]; 4],
+    },
+    Components {
+        #[serde(default)]
+        translate: V3,
+        #[serde(default)]
+        rotate: Rotation,
+        #[serde(default)]
+        scale: Scale,
+    },
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Object {
     Sphere {
+        #[serde(default)]
         pos: V3,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<Transform>,
         radius: f64,
         material: Material,
     },
     Plane {
+        #[serde(default)]
         pos: V3,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<Transform>,
         normal: V3,
         material: Material,
     },
     SDF {
+        #[serde(default)]
         pos: V3,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<Transform>,
         material: Material,
         sdf: SDF,
     },
+    Mesh {
+        #[serde(default)]
+        pos: V3,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<Transform>,
+        filename: PathBuf,
+        material: Material,
+    },
+    /// Copy of one of the named `objects` of the scene, sharing its geometry
+    Instance {
+        #[serde(default)]
+        pos: V3,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<Transform>,
+        of: String,
+        /// Material replacing the ones of the copied object
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        material_override: Option<Material>,
+    },
+    /// Objects transformed together, with their own transforms applied first
+    Group {
+        #[serde(default)]
+        pos: V3,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<Transform>,
+        children: Vec<Object>,
+    },
+}
+
+/// Homogeneous medium filling the scene, such as fog.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Atmosphere {
+    #[serde(default)]
+    pub absorption: V3,
+    #[serde(default)]
+    pub scattering: V3,
+    #[serde(default)]
+    pub anisotropy: f64,
+    /// Distance after which rays which don't hit anything, such as rays towards the sky and
+    /// the sun, leave the atmosphere
+    #[serde(default = "default_atmosphere_distance")]
+    pub distance: f64,
+}
+
+const fn default_atmosphere_distance() -> f64 {
+    100.0
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Sun {
+    /// Angle above the horizon, in degrees
+    #[serde(default = "default_sun_elevation")]
+    pub elevation: f64,
+    /// Angle around the vertical axis from +X towards +Z, in degrees
+    #[serde(default = "default_sun_azimuth")]
+    pub azimuth: f64,
+    /// Apparent diameter of the sun disc, in degrees
+    #[serde(default = "default_sun_angular_size")]
+    pub angular_size: f64,
+    #[serde(default = "default_sun_color")]
+    pub color: V3,
+    #[serde(default = "default_sun_strength")]
+    pub strength: f64,
+}
+
+impl Default for Sun {
+    fn default() -> Self {
+        Self {
+            elevation: default_sun_elevation(),
+            azimuth: default_sun_azimuth(),
+            angular_size: default_sun_angular_size(),
+            color: default_sun_color(),
+            strength: default_sun_strength(),
+        }
+    }
+}
+
+fn default_sun_elevation() -> f64 {
+    // Sun direction of (1, 1, 1)
+    (1.0 / 3f64.sqrt()).asin().to_degrees()
+}
+
+const fn default_sun_azimuth() -> f64 {
+    45.0
+}
+
+fn default_sun_angular_size() -> f64 {
+    2.0 * 0.998f64.acos().to_degrees()
+}
+
+const fn default_sun_color() -> V3 {
+    [1.0, 1.0, 1.0]
+}
+
+const fn default_sun_strength() -> f64 {
+    100.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Sky {
+    Gradient {
+        #[serde(default = "default_horizon")]
+        horizon: V3,
+        #[serde(default = "default_zenith")]
+        zenith: V3,
+        #[serde(default)]
+        sun: Option<Sun>,
+    },
+    Constant {
+        color: V3,
+    },
+    Environment {
+        filename: PathBuf,
+        /// Rotation around the vertical axis, in degrees
+        #[serde(default)]
+        rotation: f64,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
+    Physical {
+        #[serde(default)]
+        sun: Sun,
+        #[serde(default = "default_turbidity")]
+        turbidity: f64,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self::Gradient {
+            horizon: default_horizon(),
+            zenith: default_zenith(),
+            sun: Some(Sun::default()),
+        }
+    }
+}
+
+const fn default_horizon() -> V3 {
+    [1.0, 1.0, 1.0]
+}
+
+const fn default_zenith() -> V3 {
+    [0.5, 0.7, 1.0]
+}
+
+const fn default_intensity() -> f64 {
+    1.0
+}
+
+const fn default_turbidity() -> f64 {
+    3.0
+}
+
+/// Order in which image tiles are rendered.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileOrder {
+    /// Left to right, top to bottom
+    #[default]
+    Scanline,
+    /// Outwards from the center of the image
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles close to each other
+    Hilbert,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Tiles {
+    /// Width and height of the tiles, in pixels
+    #[serde(default = "default_tile_size")]
+    pub size: u32,
+    #[serde(default)]
+    pub order: TileOrder,
+}
+
+impl Default for Tiles {
+    fn default() -> Self {
+        Self {
+            size: default_tile_size(),
+            order: TileOrder::default(),
+        }
+    }
+}
+
+const fn default_tile_size() -> u32 {
+    32
+}
+
+/// Part of the image to render, either as a rectangle of pixels or as fractions of the image
+/// size, both measured from the top left corner.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Region {
+    Pixels {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Border {
+        left: f64,
+        top: f64,
+        right: f64,
+        bottom: f64,
+    },
+}
+
+/// Adaptive sampling: once a pixel has `min_samples` samples, stop sampling it when the relative
+/// standard error of its luminance drops below `threshold`. `samples` is then the maximum.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Adaptive {
+    #[serde(default = "default_min_samples")]
+    pub min_samples: u32,
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+}
+
+const fn default_min_samples() -> u32 {
+    16
+}
+
+const fn default_threshold() -> f64 {
+    0.01
+}
+
+/// Edge-preserving filter applied to the final image, guided by the albedo and normal of the
+/// first hit. Each `sigma` sets how quickly the weight of a neighbour falls off with its
+/// difference to the filtered pixel.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Denoise {
+    /// Half-width of the filter window, in pixels
+    #[serde(default = "default_denoise_radius")]
+    pub radius: u32,
+    /// Falloff with the distance in pixels
+    #[serde(default = "default_sigma_spatial")]
+    pub sigma_spatial: f64,
+    /// Falloff with the difference of tone mapped colors
+    #[serde(default = "default_sigma_color")]
+    pub sigma_color: f64,
+    #[serde(default = "default_sigma_normal")]
+    pub sigma_normal: f64,
+    #[serde(default = "default_sigma_albedo")]
+    pub sigma_albedo: f64,
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Self {
+            radius: default_denoise_radius(),
+            sigma_spatial: default_sigma_spatial(),
+            sigma_color: default_sigma_color(),
+            sigma_normal: default_sigma_normal(),
+            sigma_albedo: default_sigma_albedo(),
+        }
+    }
+}
+
+const fn default_denoise_radius() -> u32 {
+    6
+}
+
+const fn default_sigma_spatial() -> f64 {
+    3.0
+}
+
+const fn default_sigma_color() -> f64 {
+    0.3
+}
+
+const fn default_sigma_normal() -> f64 {
+    0.2
+}
+
+const fn default_sigma_albedo() -> f64 {
+    0.1
+}
+
+/// Arbitrary output variable: an extra image holding a property of the first surface seen
+/// through each pixel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aov {
+    /// Distance from the camera
+    Depth,
+    /// World-space normal, facing the camera
+    Normal,
+    Albedo,
+    /// World-space position
+    Position,
+    /// Index of the object in `world`, plus one so that the background is 0. All the objects of
+    /// a group or an instance share its index.
+    Object,
+    /// Index of the material, plus one so that the background is 0
+    Material,
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Depth => "depth",
+            Self::Normal => "normal",
+            Self::Albedo => "albedo",
+            Self::Position => "position",
+            Self::Object => "object",
+            Self::Material => "material",
+        }
+    }
+
+    /// Names of the channels of the AOV, when written as a layer of an EXR file.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Self::Depth => &["Z"],
+            Self::Normal | Self::Position => &["X", "Y", "Z"],
+            Self::Albedo => &["R", "G", "B"],
+            Self::Object | Self::Material => &["id"],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Scene<W> {
     #[serde(default = "default_bounces")]
     pub bounces: u32,
     #[serde(default = "default_samples")]
     pub samples: u32,
+    /// Seed for the random number generators; the same seed always gives the same image
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub tiles: Tiles,
+    /// Render the whole image one sample per pixel at a time, instead of tile by tile, so
+    /// that intermediate images can be written out
+    #[serde(default)]
+    pub progressive: bool,
+    /// Only render this part of the image, leaving the rest black
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<Region>,
+    /// Output only the render region instead of the full image
+    #[serde(default)]
+    pub crop: bool,
+    /// Trace each sample at a single wavelength, so that materials can depend on it
+    #[serde(default)]
+    pub spectral: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atmosphere: Option<Atmosphere>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<Adaptive>,
+    /// Extra passes to render along with the image
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aovs: Vec<Aov>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub denoise: Option<Denoise>,
     pub camera: Camera,
+    #[serde(default)]
+    pub sky: Sky,
+    /// Named objects, which are only rendered through `Instance` objects
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub objects: BTreeMap<String, Object>,
     pub world: W,
 }
 
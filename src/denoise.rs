@@ -0,0 +1,143 @@
+use cgmath::{ElementWise, InnerSpace, Zero};
+use rayon::prelude::*;
+
+use crate::{config, output::Framebuffer, Color};
+
+/// Joint bilateral filter, guided by the `albedo` and `normal` passes of the same image.
+///
+/// The albedo is divided out before filtering and multiplied back afterwards, so that texture
+/// detail is kept and only the lighting gets smoothed. Neighbours are weighted by how close
+/// they are on screen, and how similar their albedo, normal and color are to those of the
+/// filtered pixel. Colors are compared after a 3x3 box filter and tone mapping, so that
+/// isolated bright samples don't stand out from their neighbourhood.
+pub fn denoise(
+    image: &Framebuffer,
+    albedo: &Framebuffer,
+    normal: &Framebuffer,
+    settings: &config::Denoise,
+) -> Framebuffer {
+    let (width, height) = (image.width as i64, image.height as i64);
+    let index = |x: i64, y: i64| (y * width + x) as usize;
+
+    // Albedo to demodulate by, leaving pixels with a black albedo alone
+    let divisor: Vec<Color> = albedo
+        .pixels
+        .iter()
+        .map(|a| a.map(|x| if x > 1e-3 { x } else { 1.0 }))
+        .collect();
+    let irradiance: Vec<Color> = image
+        .pixels
+        .iter()
+        .zip(&divisor)
+        .map(|(c, a)| c.div_element_wise(*a))
+        .collect();
+    let reference: Vec<Color> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let mut sum = Color::zero();
+            let mut count = 0.0;
+            for (dx, dy) in (-1..=1).flat_map(|dx| (-1..=1).map(move |dy| (dx, dy))) {
+                let (qx, qy) = (x + dx, y + dy);
+                if (0..width).contains(&qx) && (0..height).contains(&qy) {
+                    sum += irradiance[index(qx, qy)];
+                    count += 1.0;
+                }
+            }
+            (sum / count).map(|c| c.max(0.0) / (1.0 + c.max(0.0)))
+        })
+        .collect();
+
+    let r = settings.radius as i64;
+    let falloff = |sigma: f64| -0.5 / (sigma * sigma).max(1e-12);
+    let (ks, kc, kn, ka) = (
+        falloff(settings.sigma_spatial),
+        falloff(settings.sigma_color),
+        falloff(settings.sigma_normal),
+        falloff(settings.sigma_albedo),
+    );
+    let pixels = (0..height)
+        .into_par_iter()
+        .flat_map_iter(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let p = index(x, y);
+            let mut sum = Color::zero();
+            let mut total = 0.0;
+            for qy in (y - r).max(0)..=(y + r).min(height - 1) {
+                for qx in (x - r).max(0)..=(x + r).min(width - 1) {
+                    let q = index(qx, qy);
+                    let d2 = ((qx - x) * (qx - x) + (qy - y) * (qy - y)) as f64;
+                    let exponent = ks * d2
+                        + kc * (reference[q] - reference[p]).magnitude2()
+                        + kn * (normal.pixels[q] - normal.pixels[p]).magnitude2()
+                        + ka * (albedo.pixels[q] - albedo.pixels[p]).magnitude2();
+                    let weight = exponent.exp();
+                    sum += irradiance[q] * weight;
+                    total += weight;
+                }
+            }
+            (sum / total).mul_element_wise(divisor[p])
+        })
+        .collect();
+    Framebuffer {
+        width: image.width,
+        height: image.height,
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framebuffer(width: u32, height: u32, pixel: impl Fn(u32, u32) -> Color) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| pixel(x, y))
+                .collect(),
+        }
+    }
+
+    /// Deterministic noise in [-0.5, 0.5]
+    fn noise(x: u32, y: u32) -> f64 {
+        let h = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) % 1000;
+        h as f64 / 1000.0 - 0.5
+    }
+
+    #[test]
+    fn smooths_noise_and_keeps_normal_edges() {
+        let (width, height) = (32, 32);
+        // Left half faces +X and is dark, right half faces +Y and is bright
+        let normal = framebuffer(width, height, |x, _| {
+            if x < width / 2 {
+                Color::unit_x()
+            } else {
+                Color::unit_y()
+            }
+        });
+        let albedo = framebuffer(width, height, |_, _| Color::new(0.5, 0.5, 0.5));
+        let clean = |x| if x < width / 2 { 0.1 } else { 0.4 };
+        let image = framebuffer(width, height, |x, y| {
+            Color::new(1.0, 1.0, 1.0) * (clean(x) * (1.0 + 0.5 * noise(x, y)))
+        });
+        let denoised = denoise(&image, &albedo, &normal, &config::Denoise::default());
+
+        let error = |fb: &Framebuffer| {
+            fb.pixels
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (c.x - clean(i as u32 % width)).powi(2))
+                .sum::<f64>()
+        };
+        assert!(error(&denoised) < 0.1 * error(&image));
+        // Pixels right next to the edge must not bleed into each other
+        for y in 0..height {
+            let left = denoised.pixels[(y * width + width / 2 - 1) as usize].x;
+            let right = denoised.pixels[(y * width + width / 2) as usize].x;
+            assert!((left - 0.1).abs() < 0.02, "{}", left);
+            assert!((right - 0.4).abs() < 0.08, "{}", right);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::f64::consts::PI;
+
+use cgmath::{InnerSpace, Matrix4, Transform};
+use rand::Rng;
+
+use crate::{
+    mesh::Mesh,
+    sky::Sun,
+    traits::HitRecord,
+    utils::{orthonormal_basis, SampleRng},
+    P3, V3,
+};
+
+/// Direction towards a point sampled on a light, along with the solid angle density of sampling
+/// that direction.
+#[derive(Copy, Clone, Debug)]
+pub struct LightSample {
+    pub dir: V3,
+    /// Distance to the sampled point, infinite for lights at infinity
+    pub dist: f64,
+    pub pdf: f64,
+}
+
+/// Light source which can be explicitly sampled for next-event estimation.
+#[derive(Clone, Debug)]
+pub enum Light {
+    /// Sun disc of the sky, subtending a cone of half-angle `acos(cos_max)`
+    Sun { dir: V3, cos_max: f64 },
+    /// Emissive sphere, sampled within the cone it subtends
+    Sphere {
+        object_id: usize,
+        leaf: usize,
+        center: P3,
+        radius: f64,
+    },
+    /// Emissive triangle mesh, sampled uniformly over its area
+    Mesh {
+        object_id: usize,
+        leaf: usize,
+        triangles: Vec<[P3; 3]>,
+        /// Cumulative triangle areas
+        cdf: Vec<f64>,
+    },
+}
+
+impl Light {
+    pub fn sun(sun: &Sun) -> Self {
+        Self::Sun {
+            dir: sun.dir,
+            cos_max: sun.cos_max,
+        }
+    }
+
+    pub fn mesh(object_id: usize, leaf: usize, mesh: &Mesh, local_to_world: &Matrix4<f64>) -> Self {
+        let triangles: Vec<_> = mesh
+            .triangles()
+            .map(|t| t.positions().map(|p| local_to_world.transform_point(p)))
+            .collect();
+        let cdf = triangles
+            .iter()
+            .scan(0.0, |acc, t| {
+                *acc += triangle_area(t);
+                Some(*acc)
+            })
+            .collect();
+        Self::Mesh {
+            object_id,
+            leaf,
+            triangles,
+            cdf,
+        }
+    }
+
+    /// Object and leaf of the emitting surface, as reported in hit records, or `None` for
+    /// lights at infinity.
+    pub fn surface(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::Sun { .. } => None,
+            Self::Sphere {
+                object_id, leaf, ..
+            }
+            | Self::Mesh {
+                object_id, leaf, ..
+            } => Some((*object_id, *leaf)),
+        }
+    }
+
+    pub fn sample(&self, rng: &mut SampleRng, from: P3) -> Option<LightSample> {
+        match self {
+            Self::Sun { dir, cos_max } => Some(LightSample {
+                dir: sample_cone(rng, *dir, *cos_max),
+                dist: f64::INFINITY,
+                pdf: cone_pdf(*cos_max),
+            }),
+            Self::Sphere { center, radius, .. } => {
+                let to_center = center - from;
+                let d2 = to_center.magnitude2();
+                if d2 <= radius * radius {
+                    return None;
+                }
+                let cos_max = (1.0 - radius * radius / d2).sqrt();
+                let dir = sample_cone(rng, to_center.normalize(), cos_max);
+                // Distance to the near side of the sphere along `dir`
+                let tc = to_center.dot(dir);
+                let dist = tc - (radius * radius - (d2 - tc * tc)).max(0.0).sqrt();
+                Some(LightSample {
+                    dir,
+                    dist,
+                    pdf: cone_pdf(cos_max),
+                })
+            }
+            Self::Mesh { triangles, cdf, .. } => {
+                let area = *cdf.last()?;
+                let x = rng.gen_range(0.0, area);
+                let idx = cdf.partition_point(|&c| c < x).min(triangles.len() - 1);
+                let [p0, p1, p2] = triangles[idx];
+                let (mut u, mut v) = (rng.gen::<f64>(), rng.gen::<f64>());
+                if u + v > 1.0 {
+                    u = 1.0 - u;
+                    v = 1.0 - v;
+                }
+                let point = p0 + u * (p1 - p0) + v * (p2 - p0);
+                let to_point = point - from;
+                let dist = to_point.magnitude();
+                let dir = to_point / dist;
+                let cos = (p1 - p0).cross(p2 - p0).normalize().dot(dir).abs();
+                if cos < 1e-8 {
+                    return None;
+                }
+                Some(LightSample {
+                    dir,
+                    dist,
+                    pdf: dist * dist / (cos * area),
+                })
+            }
+        }
+    }
+
+    /// Density with which `sample` would have produced the direction `dir` from `from`. `hit`
+    /// is where a ray leaving in that direction hits this light, if it is not at infinity.
+    pub fn pdf(&self, from: P3, dir: V3, hit: Option<&HitRecord>) -> f64 {
+        match (self, hit) {
+            (Self::Sun { dir: sun, cos_max }, _) if dir.dot(*sun) > *cos_max => cone_pdf(*cos_max),
+            (Self::Sphere { center, radius, .. }, Some(_)) => {
+                let d2 = (center - from).magnitude2();
+                if d2 <= radius * radius {
+                    0.0
+                } else {
+                    cone_pdf((1.0 - radius * radius / d2).sqrt())
+                }
+            }
+            (Self::Mesh { cdf, .. }, Some(hit)) => {
+                let area = cdf.last().copied().unwrap_or(0.0);
+                let cos = hit.geometric_normal.dot(dir).abs();
+                if area <= 0.0 || cos < 1e-8 {
+                    0.0
+                } else {
+                    hit.t * hit.t / (cos * area)
+                }
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+fn triangle_area([p0, p1, p2]: &[P3; 3]) -> f64 {
+    0.5 * (p1 - p0).cross(p2 - p0).magnitude()
+}
+
+fn cone_pdf(cos_max: f64) -> f64 {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
+/// Uniformly sample a direction within the cone around `axis` of half-angle `acos(cos_max)`.
+fn sample_cone(rng: &mut SampleRng, axis: V3, cos_max: f64) -> V3 {
+    let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    let (t, b) = orthonormal_basis(axis);
+    (t * phi.cos() * sin_theta + b * phi.sin() * sin_theta + axis * cos_theta).normalize()
+}
+
+/// Power heuristic (with exponent 2) weight of a sample from the `pdf` strategy, when combined
+/// with the `other` strategy.
+pub fn power_heuristic(pdf: f64, other: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
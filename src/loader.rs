@@ -0,0 +1,819 @@
+//! Reading scene files, with includes and named definitions resolved.
+//!
+//! Besides the scene itself, a scene file may contain:
+//!
+//! - `include`: a file or a list of files, relative to the including file. Their `world` comes
+//!   before the one of the including file, their definitions can be replaced by name, and
+//!   any other setting of the including file takes precedence.
+//! - `materials`, `sdfs` and `objects`: definitions by name. Wherever a material, SDF or
+//!   object is expected, it can be given by name, or as `{use: <name>, ...}` to replace some
+//!   fields of the definition. Definitions can refer to each other.
+//!
+//! All of them are resolved on the YAML document, so the result is a plain scene. Named
+//! objects are kept as well, resolved, for `Instance` objects to share their geometry.
+//!
+//! Asset paths (meshes, textures and environment maps) are relative to the file they are
+//! written in, like includes.
+
+use std::{
+    env,
+    fs::File,
+    path::{Component, Path, PathBuf},
+};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::config;
+
+const INCLUDE: &str = "include";
+/// Key of the asset paths, wherever they are in the scene
+const FILENAME: &str = "filename";
+/// Top-level keys holding definitions, which are merged by name across included files
+const LIBRARIES: [&str; 3] = ["materials", "sdfs", "objects"];
+
+/// Read the scene file at `path`, with `overrides` applied once includes are merged, but
+/// before names are resolved.
+pub fn load(
+    path: &Path,
+    overrides: &[(String, Value)],
+) -> Result<config::Scene<Vec<config::Object>>, String> {
+    let mut doc = read(path, &mut vec![])?;
+    for (key, value) in overrides {
+        crate::cli::set_value(&mut doc, key, value.clone())?;
+    }
+    let doc = resolve(doc).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_path_to_error::deserialize(doc).map_err(|e| match e.path().to_string().as_str() {
+        "." => format!("{}: {}", path.display(), e.inner()),
+        at => format!("{}: {}: {}", path.display(), at, e.inner()),
+    })
+}
+
+/// Formats scenes can be written in, selected from the file extension.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SceneFormat {
+    Yaml,
+    Json,
+}
+
+impl SceneFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "yml" | "yaml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Write a loaded scene back out, with every default value spelled out. Named objects are
+/// kept for the instances which refer to them, and JSON can be loaded back as YAML. Asset
+/// paths are made relative to `dir`, where the scene is written.
+pub fn dump(scene: &config::Scene<Vec<config::Object>>, format: SceneFormat, dir: &Path) -> String {
+    let mut doc = serde_yaml::to_value(scene).expect("Cannot write scene as YAML");
+    map_filenames(&mut doc, &mut |path| relative_to(path, dir));
+    match format {
+        SceneFormat::Yaml => serde_yaml::to_string(&doc).expect("Cannot write scene as YAML"),
+        SceneFormat::Json => {
+            serde_json::to_string_pretty(&doc).expect("Cannot write scene as JSON") + "\n"
+        }
+    }
+}
+
+/// Replace every asset path of `doc` with the result of `map`.
+fn map_filenames<F: FnMut(&Path) -> PathBuf>(doc: &mut Value, map: &mut F) {
+    match doc {
+        Value::Mapping(mapping) => {
+            for (k, v) in mapping.iter_mut() {
+                match v {
+                    Value::String(path) if *k == key(FILENAME) => {
+                        *path = map(Path::new(path)).to_string_lossy().into_owned()
+                    }
+                    _ => map_filenames(v, map),
+                }
+            }
+        }
+        Value::Sequence(values) => values.iter_mut().for_each(|v| map_filenames(v, map)),
+        _ => {}
+    }
+}
+
+/// `path` as seen from `dir`, both being absolute or relative to the current directory. The
+/// paths aren't resolved on the file system, so absolute paths are kept as they are.
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    let cwd = env::current_dir().unwrap_or_default();
+    let absolute = |p: &Path| {
+        let mut absolute = PathBuf::new();
+        for c in cwd.join(p).components() {
+            match c {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    absolute.pop();
+                }
+                c => absolute.push(c),
+            }
+        }
+        absolute
+    };
+    let (path, dir) = (absolute(path), absolute(dir));
+    let common = path
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    dir.components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(path.components().skip(common))
+        .collect()
+}
+
+/// Read a YAML file and merge in the files it includes. `stack` holds the files being read,
+/// to detect include cycles.
+fn read(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value, String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if stack.contains(&canonical) {
+        return Err(format!("{}: included from itself", path.display()));
+    }
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut doc: Value =
+        serde_yaml::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let map = match doc.as_mapping_mut() {
+        Some(map) => map,
+        None => return Ok(doc),
+    };
+    let includes = match map.remove(&key(INCLUDE)) {
+        None => vec![],
+        Some(Value::String(file)) => vec![file],
+        Some(Value::Sequence(files)) => files
+            .into_iter()
+            .map(|f| match f {
+                Value::String(file) => Ok(file),
+                _ => Err(format!(
+                    "{}: `include` must list file names",
+                    path.display()
+                )),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(format!(
+                "{}: `include` must be a file name or a list of them",
+                path.display()
+            ))
+        }
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    map_filenames(&mut doc, &mut |asset| dir.join(asset));
+
+    stack.push(canonical);
+    let mut merged = Mapping::new();
+    for include in includes {
+        let include = dir.join(include);
+        match read(&include, stack)? {
+            Value::Mapping(included) => merge(&mut merged, included),
+            Value::Null => {}
+            _ => return Err(format!("{}: not a mapping", include.display())),
+        }
+    }
+    stack.pop();
+    merge(&mut merged, doc.as_mapping().unwrap().clone());
+    Ok(Value::Mapping(merged))
+}
+
+/// Merge `other` into `base`, `other` taking precedence.
+fn merge(base: &mut Mapping, other: Mapping) {
+    for (k, v) in other {
+        match (base.get_mut(&k), v) {
+            (Some(Value::Sequence(world)), Value::Sequence(more)) if k == key("world") => {
+                world.extend(more)
+            }
+            (Some(Value::Mapping(library)), Value::Mapping(more))
+                if LIBRARIES.iter().any(|l| k == key(l)) =>
+            {
+                for (name, definition) in more {
+                    library.insert(name, definition);
+                }
+            }
+            (_, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_string())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Material,
+    SDF,
+    Object,
+}
+
+impl Kind {
+    fn library(self) -> &'static str {
+        match self {
+            Self::Material => "materials",
+            Self::SDF => "sdfs",
+            Self::Object => "objects",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Material => "material",
+            Self::SDF => "SDF",
+            Self::Object => "object",
+        }
+    }
+}
+
+/// Named definitions of a scene file.
+struct Library {
+    definitions: Mapping,
+    /// Definitions being resolved, to detect cycles
+    stack: Vec<(Kind, String)>,
+}
+
+/// Replace every reference to a named definition in the scene by the definition itself, and
+/// remove the definitions, except for objects.
+fn resolve(mut doc: Value) -> Result<Value, String> {
+    let map = match doc.as_mapping_mut() {
+        Some(map) => map,
+        None => return Ok(doc),
+    };
+    let mut library = Library {
+        definitions: Mapping::new(),
+        stack: vec![],
+    };
+    for name in &LIBRARIES {
+        match map.remove(&key(name)) {
+            None => {}
+            Some(definitions @ Value::Mapping(_)) => {
+                library.definitions.insert(key(name), definitions);
+            }
+            Some(_) => return Err(format!("`{}` must map names to definitions", name)),
+        }
+    }
+    if let Some(Value::Sequence(world)) = map.get_mut(&key("world")) {
+        for object in world {
+            *object = library.resolve(Kind::Object, object.clone())?;
+        }
+    }
+    if let Some(Value::Mapping(objects)) = library.definitions.get(&key("objects")).cloned() {
+        let objects = objects
+            .into_iter()
+            .map(|(name, o)| Ok((name, library.resolve(Kind::Object, o)?)))
+            .collect::<Result<Mapping, String>>()?;
+        map.insert(key("objects"), Value::Mapping(objects));
+    }
+    Ok(doc)
+}
+
+impl Library {
+    /// Resolve a material, SDF or object, along with the ones nested in it.
+    fn resolve(&mut self, kind: Kind, value: Value) -> Result<Value, String> {
+        let mut value = match value {
+            Value::String(name) => self.definition(kind, name)?,
+            Value::Mapping(mut fields) => match fields.remove(&key("use")) {
+                Some(Value::String(name)) => {
+                    let mut definition = self.definition(kind, name)?;
+                    if let Some(map) = definition.as_mapping_mut() {
+                        for (k, v) in fields {
+                            map.insert(k, v);
+                        }
+                    }
+                    definition
+                }
+                Some(_) => return Err(format!("`use` must be the name of a {}", kind.name())),
+                None => Value::Mapping(fields),
+            },
+            value => value,
+        };
+        // Fields that hold other definitions
+        let nested: &[(&str, Kind)] = match kind {
+            Kind::Material => &[
+                ("base", Kind::Material),
+                ("a", Kind::Material),
+                ("b", Kind::Material),
+            ],
+            Kind::SDF => &[
+                ("sdf", Kind::SDF),
+                ("left", Kind::SDF),
+                ("right", Kind::SDF),
+            ],
+            Kind::Object => &[
+                ("material", Kind::Material),
+                ("material_override", Kind::Material),
+                ("sdf", Kind::SDF),
+                ("children", Kind::Object),
+            ],
+        };
+        if let Some(map) = value.as_mapping_mut() {
+            for &(field, kind) in nested {
+                match map.get_mut(&key(field)) {
+                    Some(Value::Sequence(items)) => {
+                        for item in items {
+                            *item = self.resolve(kind, item.clone())?;
+                        }
+                    }
+                    Some(v) => *v = self.resolve(kind, v.clone())?,
+                    None => {}
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Resolved definition of `name`.
+    fn definition(&mut self, kind: Kind, name: String) -> Result<Value, String> {
+        let definition = self
+            .definitions
+            .get(&key(kind.library()))
+            .and_then(|l| l.as_mapping())
+            .and_then(|l| l.get(&key(&name)))
+            .cloned()
+            .ok_or_else(|| format!("unknown {} `{}`", kind.name(), name))?;
+        if self.stack.contains(&(kind, name.clone())) {
+            return Err(format!(
+                "{} `{}` is defined in terms of itself",
+                kind.name(),
+                name
+            ));
+        }
+        self.stack.push((kind, name));
+        let resolved = self.resolve(kind, definition);
+        self.stack.pop();
+        resolved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rand::Rng;
+
+    use super::*;
+    use crate::{output::Accumulator, scene::Scene, utils::sample_rng, utils::SampleRng};
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn named_definitions() {
+        let doc = yaml(
+            "
+materials:
+  red: {type: Lambert, albedo: {color: [1, 0, 0]}}
+  glow: {type: Emissive, color: {color: [1, 1, 1]}, base: red}
+sdfs:
+  ball: {type: Sphere, radius: 1}
+  blob: {type: Union, smooth: 0.1, left: {use: ball, pos: [1, 0, 0]}, right: ball}
+objects:
+  lamp: {type: Sphere, radius: 0.5, material: glow}
+world:
+  - {type: SDF, sdf: blob, material: {use: red, albedo: {color: [0, 1, 0]}}}
+  - {use: lamp, pos: [0, 2, 0]}
+",
+        );
+        let expected = yaml(
+            "
+world:
+  - type: SDF
+    sdf:
+      type: Union
+      smooth: 0.1
+      left: {type: Sphere, radius: 1, pos: [1, 0, 0]}
+      right: {type: Sphere, radius: 1}
+    material: {type: Lambert, albedo: {color: [0, 1, 0]}}
+  - type: Sphere
+    radius: 0.5
+    material:
+      type: Emissive
+      color: {color: [1, 1, 1]}
+      base: {type: Lambert, albedo: {color: [1, 0, 0]}}
+    pos: [0, 2, 0]
+objects:
+  lamp:
+    type: Sphere
+    radius: 0.5
+    material:
+      type: Emissive
+      color: {color: [1, 1, 1]}
+      base: {type: Lambert, albedo: {color: [1, 0, 0]}}
+",
+        );
+        assert_eq!(resolve(doc).unwrap(), expected);
+    }
+
+    #[test]
+    fn layered_materials() {
+        let doc = yaml(
+            "
+materials:
+  red: {type: Lambert, albedo: {color: [1, 0, 0]}}
+  mirror: {type: Metal, albedo: {color: [1, 1, 1]}}
+  worn: {type: Mix, a: red, b: {use: mirror, fuzz: 0.2}, factor: 0.5}
+world:
+  - {type: Sphere, radius: 1, material: {type: Coated, base: worn}}
+",
+        );
+        let expected = yaml(
+            "
+world:
+  - type: Sphere
+    radius: 1
+    material:
+      type: Coated
+      base:
+        type: Mix
+        a: {type: Lambert, albedo: {color: [1, 0, 0]}}
+        b: {type: Metal, albedo: {color: [1, 1, 1]}, fuzz: 0.2}
+        factor: 0.5
+",
+        );
+        assert_eq!(resolve(doc).unwrap(), expected);
+    }
+
+    #[test]
+    fn invalid_references() {
+        let unknown = yaml("world: [{type: Sphere, radius: 1, material: gold}]");
+        assert!(resolve(unknown).is_err());
+        let cycle = yaml("materials: {a: {use: b}, b: {use: a}}\nworld: [{material: a}]");
+        assert!(resolve(cycle).is_err());
+        // Only referenced definitions need to be valid
+        let unused = yaml("materials: {a: {use: b}, b: {use: a}}\nworld: []");
+        assert!(resolve(unused).is_ok());
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("raytracer-includes-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib/materials.yml"),
+            "materials: {red: {type: Metal}, blue: {type: Lambert}}\n\
+             world: [{type: Sphere, material: red}]\n\
+             samples: 1",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("scene.yml"),
+            "include: lib/materials.yml\n\
+             materials: {red: {type: Dielectric}}\n\
+             world: [{type: Plane, material: blue}]\n\
+             samples: 2",
+        )
+        .unwrap();
+        fs::write(dir.join("cycle.yml"), "include: [cycle.yml]").unwrap();
+
+        let doc = resolve(read(&dir.join("scene.yml"), &mut vec![]).unwrap()).unwrap();
+        let expected = yaml(
+            "world: [{type: Sphere, material: {type: Dielectric}}, \
+             {type: Plane, material: {type: Lambert}}]\n\
+             samples: 2",
+        );
+        let cycle = read(&dir.join("cycle.yml"), &mut vec![]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(doc, expected);
+        assert!(cycle.is_err());
+    }
+
+    #[test]
+    fn asset_paths() {
+        let dir = std::env::temp_dir().join(format!("raytracer-assets-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib/textures")).unwrap();
+        fs::create_dir_all(dir.join("out")).unwrap();
+        fs::write(
+            dir.join("lib/materials.yml"),
+            "materials: {wood: {type: Lambert, albedo: {filename: textures/wood.png}}}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("scene.yml"),
+            "include: lib/materials.yml\n\
+             world: [{type: Mesh, filename: teapot.obj, material: wood}]\n\
+             camera: {pos: [0, 0, 0], look_at: [0, 0, 1], up: [0, 1, 0]}",
+        )
+        .unwrap();
+        let filenames = |scene: &config::Scene<Vec<config::Object>>| match &scene.world[0] {
+            config::Object::Mesh {
+                filename,
+                material: config::Material::Lambert { albedo },
+                ..
+            } => match albedo {
+                config::ColorInput::Texture { filename: t, .. } => (filename.clone(), t.clone()),
+                _ => panic!("{:?}", albedo),
+            },
+            o => panic!("{:?}", o),
+        };
+
+        let scene = load(&dir.join("scene.yml"), &[]).unwrap();
+        let (mesh, texture) = filenames(&scene);
+        assert_eq!(mesh, dir.join("teapot.obj"));
+        assert_eq!(texture, dir.join("lib/textures/wood.png"));
+        // Dumped elsewhere, the paths still lead to the same files. They are absolute here, but
+        // relative ones go through `relative_to`.
+        let out = dir.join("out/scene.yml");
+        fs::write(&out, dump(&scene, SceneFormat::Yaml, &dir.join("out"))).unwrap();
+        let dumped = load(&out, &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(filenames(&dumped), (mesh, texture));
+
+        let relative = |path, dir| relative_to(Path::new(path), Path::new(dir));
+        assert_eq!(relative("lib/wood.png", ""), Path::new("lib/wood.png"));
+        assert_eq!(
+            relative("lib/wood.png", "out"),
+            Path::new("../lib/wood.png")
+        );
+        assert_eq!(
+            relative("./a/../wood.png", "a/b"),
+            Path::new("../../wood.png")
+        );
+        assert_eq!(relative("a/wood.png", "a/./"), Path::new("wood.png"));
+        assert_eq!(relative("/wood.png", "a"), Path::new("/wood.png"));
+    }
+
+    #[test]
+    fn undecodable_environment() {
+        let dir = std::env::temp_dir().join(format!("raytracer-sky-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sky.hdr"), "not an image").unwrap();
+        fs::write(
+            dir.join("scene.yml"),
+            "world: []\n\
+             camera: {pos: [0, 0, 0], look_at: [0, 0, 1], up: [0, 1, 0]}\n\
+             sky: {type: Environment, filename: sky.hdr}",
+        )
+        .unwrap();
+        let built = Scene::build(load(&dir.join("scene.yml"), &[]).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        let error = built.unwrap_err();
+        assert!(error.starts_with("cannot load environment"), "{}", error);
+    }
+
+    fn float(rng: &mut SampleRng, low: f64, high: f64) -> String {
+        format!("{:?}", rng.gen_range(low, high))
+    }
+
+    fn vector(rng: &mut SampleRng, low: f64, high: f64) -> String {
+        let x = float(rng, low, high);
+        let y = float(rng, low, high);
+        format!("[{}, {}, {}]", x, y, float(rng, low, high))
+    }
+
+    fn color_input(rng: &mut SampleRng) -> String {
+        let (low, high) = (vector(rng, 0.0, 1.0), vector(rng, 0.0, 1.0));
+        let scale = float(rng, 0.1, 2.0);
+        match rng.gen_range(0, 3) {
+            0 => format!("{{color: {}}}", low),
+            1 => format!("{{even: {}, odd: {}, scale: {}}}", low, high, scale),
+            _ => format!(
+                "{{low: {}, high: {}, scale: {}, octaves: 3}}",
+                low, high, scale
+            ),
+        }
+    }
+
+    fn material(rng: &mut SampleRng) -> String {
+        let albedo = color_input(rng);
+        match rng.gen_range(0, 8) {
+            0 => format!("{{type: Lambert, albedo: {}}}", albedo),
+            1 => format!(
+                "{{type: Metal, albedo: {}, fuzz: {}}}",
+                albedo,
+                float(rng, 0.0, 0.5)
+            ),
+            2 => format!(
+                "{{type: Dielectric, albedo: {}, ior: {}, roughness: {}}}",
+                albedo,
+                ior(rng),
+                float(rng, 0.0, 0.5)
+            ),
+            3 => format!(
+                "{{type: Principled, base_color: {}, metallic: {}, roughness: {}, specular: {}}}",
+                albedo,
+                float(rng, 0.0, 1.0),
+                float(rng, 0.0, 1.0),
+                float(rng, 0.0, 1.0)
+            ),
+            4 => format!(
+                "{{type: Volume, absorption: {}, scattering: {}, anisotropy: {}}}",
+                vector(rng, 0.0, 2.0),
+                vector(rng, 0.0, 5.0),
+                float(rng, -0.5, 0.9)
+            ),
+            5 => format!(
+                "{{type: Mix, a: {{type: Lambert, albedo: {}}}, \
+                 b: {{type: Metal, albedo: {}, fuzz: {}}}, factor: {}}}",
+                albedo,
+                color_input(rng),
+                float(rng, 0.0, 0.5),
+                if rng.gen() {
+                    float(rng, 0.0, 1.0)
+                } else {
+                    format!(
+                        "{{even: [0, 0, 0], odd: [1, 1, 1], scale: {}}}",
+                        float(rng, 1.0, 8.0)
+                    )
+                }
+            ),
+            6 => format!(
+                "{{type: Coated, base: {{type: Lambert, albedo: {}}}, coat_ior: {}, \
+                 coat_roughness: {}}}",
+                albedo,
+                float(rng, 1.2, 2.0),
+                float(rng, 0.0, 0.5)
+            ),
+            _ => format!(
+                "{{type: Emissive, color: {}, strength: {}, base: {{type: Lambert, albedo: {}}}}}",
+                albedo,
+                float(rng, 0.5, 5.0),
+                color_input(rng)
+            ),
+        }
+    }
+
+    fn ior(rng: &mut SampleRng) -> String {
+        match rng.gen_range(0, 3) {
+            0 => float(rng, 1.1, 2.0),
+            1 => format!(
+                "{{a: {}, b: {}}}",
+                float(rng, 1.1, 2.0),
+                float(rng, 0.0, 0.05)
+            ),
+            _ => format!(
+                "{{b: {}, c: [{}, {}, 100]}}",
+                vector(rng, 0.1, 1.0),
+                float(rng, 0.0, 0.01),
+                float(rng, 0.01, 0.05)
+            ),
+        }
+    }
+
+    fn transform(rng: &mut SampleRng) -> String {
+        let (translate, rotate) = (vector(rng, -1.0, 1.0), vector(rng, -180.0, 180.0));
+        let (axis, angle) = (vector(rng, 0.1, 1.0), float(rng, -90.0, 90.0));
+        let (scale, scales) = (float(rng, 0.5, 2.0), vector(rng, 0.5, 2.0));
+        match rng.gen_range(0, 4) {
+            0 => "{}".to_string(),
+            1 => format!(
+                "{{translate: {}, rotate: {}, scale: {}}}",
+                translate, rotate, scale
+            ),
+            2 => format!(
+                "{{rotate: {{axis: {}, angle: {}}}, scale: {}}}",
+                axis, angle, scales
+            ),
+            _ => format!(
+                "{{matrix: [[1, {}, 0, 0], [0, 1, 0, {}], [0, 0, {}, 0], [0, 0, 0, 1]]}}",
+                float(rng, -0.5, 0.5),
+                float(rng, -1.0, 1.0),
+                scale
+            ),
+        }
+    }
+
+    /// Random scene file, using most features of the format.
+    fn random_scene(rng: &mut SampleRng) -> String {
+        let mut lines = vec![
+            format!(
+                "camera: {{pos: {}, look_at: {}, up: [0, 1, 0], aperture: {}, fov: {}}}",
+                vector(rng, 3.0, 5.0),
+                vector(rng, -0.5, 0.5),
+                float(rng, 0.0, 0.1),
+                float(rng, 30.0, 70.0)
+            ),
+            format!(
+                "samples: 2\nbounces: 3\nseed: {}\nspectral: {}",
+                rng.gen_range(0, 1000),
+                rng.gen::<bool>()
+            ),
+        ];
+        let (elevation, azimuth) = (float(rng, 10.0, 80.0), float(rng, 0.0, 360.0));
+        lines.push(match rng.gen_range(0, 3) {
+            0 => format!("sky: {{type: Constant, color: {}}}", vector(rng, 0.0, 1.0)),
+            1 => format!(
+                "sky: {{type: Physical, turbidity: {}, sun: {{elevation: {}, azimuth: {}}}}}",
+                float(rng, 2.0, 6.0),
+                elevation,
+                azimuth
+            ),
+            _ => format!(
+                "sky: {{type: Gradient, sun: {{elevation: {}, azimuth: {}, strength: {}}}}}",
+                elevation,
+                azimuth,
+                float(rng, 1.0, 50.0)
+            ),
+        });
+        if rng.gen() {
+            lines.push(format!(
+                "atmosphere: {{scattering: {}, absorption: {}, distance: {}}}",
+                vector(rng, 0.0, 0.1),
+                vector(rng, 0.0, 0.05),
+                float(rng, 5.0, 50.0)
+            ));
+        }
+        lines.push(format!("materials: {{shared: {}}}", material(rng)));
+        lines.push(format!(
+            "sdfs: {{blob: {{type: Union, smooth: {}, \
+             left: {{type: Sphere, pos: {}, radius: {}}}, \
+             right: {{type: Box, pos: [0, 0, 0], size: {}}}}}}}",
+            float(rng, 0.0, 0.3),
+            vector(rng, -0.3, 0.3),
+            float(rng, 0.2, 0.5),
+            vector(rng, 0.1, 0.4)
+        ));
+        lines.push(format!(
+            "objects: {{blob: {{type: SDF, transform: {}, material: shared, sdf: blob}}}}",
+            transform(rng)
+        ));
+        lines.push(format!(
+            "world:\n  - {{type: Plane, pos: [0, -1, 0], normal: [{}, 1, {}], material: shared}}",
+            float(rng, -0.2, 0.2),
+            float(rng, -0.2, 0.2)
+        ));
+        for _ in 0..rng.gen_range(2, 6) {
+            let placement = format!(
+                "pos: {}, transform: {}",
+                vector(rng, -1.5, 1.5),
+                transform(rng)
+            );
+            let material = material(rng);
+            lines.push(match rng.gen_range(0, 4) {
+                0 => format!(
+                    "  - {{type: Sphere, {}, radius: {}, material: {}}}",
+                    placement,
+                    float(rng, 0.2, 0.8),
+                    material
+                ),
+                1 => format!(
+                    "  - {{type: SDF, {}, material: {}, sdf: {{type: Rounding, amount: {}, \
+                     sdf: {{type: Box, size: {}}}}}}}",
+                    placement,
+                    material,
+                    float(rng, 0.0, 0.1),
+                    vector(rng, 0.1, 0.5)
+                ),
+                2 => format!(
+                    "  - {{type: Instance, of: blob, {}, material_override: {}}}",
+                    placement, material
+                ),
+                _ => format!(
+                    "  - {{type: Group, {}, children: [{{type: Instance, of: blob}}, \
+                     {{type: Sphere, pos: [1, 0, 0], radius: 0.3, material: shared}}]}}",
+                    placement
+                ),
+            });
+        }
+        lines.join("\n")
+    }
+
+    fn render(scene: config::Scene<Vec<config::Object>>) -> Vec<[u64; 3]> {
+        let (width, height) = (16, 12);
+        let mut accumulator = Accumulator::new(width, height);
+        for block in Scene::build(scene).unwrap().run(width, height) {
+            accumulator.add(&block);
+        }
+        let pixels = accumulator.framebuffer().pixels;
+        pixels
+            .iter()
+            .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+            .collect()
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let dir = std::env::temp_dir().join(format!("raytracer-dump-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for seed in 0..8 {
+            let original = dir.join(format!("scene{}.yml", seed));
+            fs::write(&original, random_scene(&mut sample_rng(seed, 0, 0, 0))).unwrap();
+            let scene = load(&original, &[]).unwrap();
+            let image = render(scene.clone());
+            assert!(
+                image.iter().any(|p| *p != image[0]),
+                "seed {} renders a flat image",
+                seed
+            );
+            for &(format, ext) in &[(SceneFormat::Yaml, "yml"), (SceneFormat::Json, "json")] {
+                let dumped = dir.join(format!("scene{}.dump.{}", seed, ext));
+                fs::write(&dumped, dump(&scene, format, &dir)).unwrap();
+                let reloaded = load(&dumped, &[]).unwrap();
+                assert_eq!(dump(&reloaded, format, &dir), dump(&scene, format, &dir));
+                assert!(render(reloaded) == image, "seed {}, {:?}", seed, format);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,57 +1,194 @@
-use std::{fs::File, time::Instant};
+#![allow(clippy::upper_case_acronyms)]
+
+use std::{fs, path::Path, process, time::Instant};
 
 use cgmath::{Point3, Vector3};
 use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::{objects::Object, scene::Scene};
+use crate::{
+    config::Aov,
+    loader::SceneFormat,
+    output::{Accumulator, Format, Framebuffer},
+    scene::Scene,
+};
 
+mod aabb;
+mod bvh;
 mod camera;
+mod cli;
 mod config;
+mod denoise;
+mod light;
+mod loader;
 mod material;
+mod medium;
+mod mesh;
 mod objects;
+mod output;
 mod ray;
 mod scene;
 mod sdf;
 mod sky;
+mod spectrum;
+mod texture;
+mod tiles;
 mod traits;
 mod utils;
+mod validate;
 
 type P3 = Point3<f64>;
 type V3 = Vector3<f64>;
 type Color = V3;
 
 fn main() {
-    let mut args = std::env::args().skip(1);
-    let config_file = args.next().unwrap();
-    let width = args.next().and_then(|p| p.parse().ok()).unwrap_or(800);
-    let height = args
-        .next()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or_else(|| (width as f64 * 9.0 / 16.0) as u32);
-
-    let file = File::open(config_file).unwrap();
-    let scn = Scene::<Vec<_>>::from(
-        serde_yaml::from_reader::<_, config::Scene<Vec<config::Object>>>(file).unwrap(),
-    )
-    .map_world::<Vec<Object>, _>(|w| w.into_iter().map(|o| o.into()).collect());
-    let bar = ProgressBar::new(height as u64).with_style(
-        ProgressStyle::default_bar()
-            .template("[{percent:>3} %] {bar:40} [{elapsed_precise} - ETA {eta_precise}]"),
-    );
-    println!("P3\n{} {}\n255\n", width, height);
+    let args = match cli::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!(
+                "error: {}\n\n{}\nFor more information, try `--help`.",
+                e,
+                cli::USAGE
+            );
+            process::exit(2);
+        }
+    };
+    if args.help {
+        print!("{}", cli::HELP);
+        return;
+    }
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+    let (width, height, bit_depth) = (args.width, args.height, args.bit_depth);
+    let config = loader::load(&args.scene, &args.overrides).unwrap_or_else(|e| fail(e));
+
+    let problems = validate::validate(&config);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == validate::Severity::Error)
+        .count();
+    if args.check || errors > 0 {
+        eprintln!(
+            "{}: {} error(s), {} warning(s)",
+            args.scene.display(),
+            errors,
+            problems.len() - errors
+        );
+        process::exit(if errors > 0 { 1 } else { 0 });
+    }
+    if args.dump {
+        let format = args.output.as_ref().and_then(SceneFormat::from_path);
+        let dir = args.output.as_ref().and_then(|p| p.parent());
+        let text = loader::dump(
+            &config,
+            format.unwrap_or(SceneFormat::Yaml),
+            dir.unwrap_or_else(|| Path::new("")),
+        );
+        match &args.output {
+            Some(path) => {
+                fs::write(path, text).unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)))
+            }
+            None => print!("{}", text),
+        }
+        return;
+    }
+    let mut scn = Scene::build(config).unwrap_or_else(|e| fail(e));
+    let (progressive, max_samples, aovs) = (scn.progressive, scn.samples, scn.aovs.clone());
+    let area = scn.render_area(width, height);
+    if area.width == 0 || area.height == 0 {
+        fail("the render region is empty");
+    }
+    // Images keep the full size unless cropping, so that the region lines up with full renders
+    let crop = scn.crop;
+    let output = |fb: Framebuffer| if crop { fb.crop(&area) } else { fb };
+    if args.denoise && scn.denoise.is_none() {
+        scn.denoise = Some(config::Denoise::default());
+    }
+    // The denoiser is guided by AOVs, which are rendered after the requested ones if needed
+    let denoise = scn.denoise.map(|settings| {
+        let mut guide = |aov| {
+            scn.aovs.iter().position(|a| *a == aov).unwrap_or_else(|| {
+                scn.aovs.push(aov);
+                scn.aovs.len() - 1
+            })
+        };
+        (settings, guide(Aov::Albedo), guide(Aov::Normal))
+    });
+    let bar = if args.quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(area.width as u64 * area.height as u64 * scn.samples as u64).with_style(
+            ProgressStyle::default_bar()
+                .template("[{percent:>3} %] {bar:40} [{elapsed_precise} - ETA {eta_precise}]"),
+        )
+    };
     let start = Instant::now();
-    bar.inc(1);
-    for row in scn.run(width, height) {
-        bar.inc(1);
-        row.into_iter().for_each(write_color);
+    let mut accumulator = Accumulator::new(width, height);
+    let mut pass = 0;
+    for block in scn.run(width, height) {
+        // Passes are rendered one after the other, so the previous one is complete
+        if block.pass != pass {
+            pass = block.pass;
+            if let Some(path) = args.output.as_ref().filter(|_| progressive) {
+                let written = output(accumulator.framebuffer()).write(path, bit_depth);
+                written.unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)));
+            }
+        }
+        bar.inc(block.samples.iter().map(|&s| s as u64).sum());
+        accumulator.add(&block);
     }
+    let mut framebuffer = accumulator.framebuffer();
+    if let Some((settings, albedo, normal)) = denoise {
+        framebuffer = denoise::denoise(
+            &framebuffer,
+            &accumulator.aov(albedo),
+            &accumulator.aov(normal),
+            &settings,
+        );
+    }
+    let framebuffer = output(framebuffer);
     let duration = Instant::now() - start;
     bar.finish_with_message(&format!("Duration: {:2.2} s", duration.as_secs_f32()));
+
+    let written = match &args.output {
+        // EXR files can hold the AOVs as extra layers, other formats get one file per AOV
+        Some(path) if !aovs.is_empty() && Format::from_path(path) == Some(Format::Exr) => {
+            let layers: Vec<_> = aovs
+                .iter()
+                .enumerate()
+                .map(|(k, aov)| (aov.name(), aov.channels(), output(accumulator.aov(k))))
+                .collect();
+            framebuffer.write_exr_layers(path, &layers)
+        }
+        Some(path) => framebuffer.write(path, bit_depth).and_then(|_| {
+            aovs.iter().enumerate().try_for_each(|(k, aov)| {
+                let mut name = path.file_stem().unwrap().to_os_string();
+                name.push(format!(".{}.", aov.name()));
+                name.push(path.extension().unwrap());
+                output(accumulator.aov(k)).write(path.with_file_name(name), bit_depth)
+            })
+        }),
+        None => {
+            if !aovs.is_empty() {
+                eprintln!("warning: AOVs are only written along with an output file");
+            }
+            framebuffer.write_ppm(std::io::stdout().lock())
+        }
+    };
+    written.unwrap_or_else(|e| fail(format!("cannot write image: {}", e)));
+    if let Some(path) = &args.sample_map {
+        let written = output(accumulator.sample_map(max_samples)).write(path, 8);
+        written.unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)));
+    }
 }
 
-fn write_color(col: Color) {
-    let col = col
-        .map(|x| x.min(1.0).max(0.0).sqrt() * 255.999)
-        .map(|x| x as u8);
-    println!("{} {} {}", col.x, col.y, col.z);
+fn fail<E: std::fmt::Display>(e: E) -> ! {
+    eprintln!("error: {}", e);
+    process::exit(1);
 }
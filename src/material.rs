@@ -1,50 +1,143 @@
-use cgmath::InnerSpace;
-use rand::{thread_rng, Rng};
+use cgmath::{Array, ElementWise, InnerSpace, Zero};
+use rand::Rng;
 
+use crate::medium::Medium;
 use crate::ray::Ray;
+use crate::texture::Texture;
 use crate::traits::HitRecord;
-use crate::utils::random_vector;
+use crate::utils::{orthonormal_basis, random_vector, SampleRng};
 use crate::V3;
 use crate::{config, Color};
+use std::convert::{TryFrom, TryInto};
+use std::f64::consts::PI;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum Material {
-    Holdout { albedo: Color },
-    Lambert { albedo: Color },
-    Metal { albedo: Color, fuzz: f64 },
-    Dielectric { transmittance: Color, ior: f64 },
+    Holdout {
+        albedo: Texture,
+    },
+    Lambert {
+        albedo: Texture,
+    },
+    Metal {
+        albedo: Texture,
+        fuzz: f64,
+    },
+    /// Refractive material absorbing light along the distance travelled inside it. Rough
+    /// interfaces scatter around the mirror and refraction directions.
+    Dielectric {
+        transmittance: Texture,
+        ior: config::Ior,
+        roughness: f64,
+    },
+    /// Light-emitting surface, optionally on top of a base material which scatters light.
+    Emissive {
+        color: Texture,
+        strength: f64,
+        base: Option<Box<Material>>,
+    },
+    /// GGX microfacet specular layer over a Lambertian base, blending into a metal as
+    /// `metallic` goes to 1.
+    Principled {
+        base_color: Texture,
+        metallic: f64,
+        roughness: f64,
+        specular: f64,
+    },
+    /// Boundary of a medium filling a closed object.
+    Volume {
+        medium: Medium,
+    },
+    /// Picks `a` or `b` at random at each bounce, `b` with probability `factor`.
+    Mix {
+        a: Box<Material>,
+        b: Box<Material>,
+        factor: Texture,
+    },
+    /// Dielectric coat over `base`. Light refracted by the coat isn't bent, and reaches the base
+    /// attenuated by the Fresnel transmittance on the way in and out.
+    Coated {
+        base: Box<Material>,
+        ior: f64,
+        roughness: f64,
+    },
 }
 
-impl From<config::Material> for Material {
-    fn from(m: config::Material) -> Self {
+impl TryFrom<config::Material> for Material {
+    type Error = String;
+
+    fn try_from(m: config::Material) -> Result<Self, String> {
         use config::Material::*;
-        match m {
-            Lambert {
-                albedo: config::ColorInput::Color { color },
-            } => Self::Lambert {
-                albedo: color.into(),
+        let boxed = |m: config::Material| Self::try_from(m).map(Box::new);
+        Ok(match m {
+            Lambert { albedo } => Self::Lambert {
+                albedo: albedo.try_into()?,
             },
-            Metal {
-                fuzz,
-                albedo: config::ColorInput::Color { color },
-            } => Self::Metal {
-                albedo: color.into(),
+            Metal { fuzz, albedo } => Self::Metal {
+                albedo: albedo.try_into()?,
                 fuzz,
             },
             Dielectric {
                 ior,
-                albedo: config::ColorInput::Color { color },
+                albedo,
+                roughness,
             } => Self::Dielectric {
-                transmittance: color.into(),
+                transmittance: albedo.try_into()?,
                 ior,
+                roughness,
             },
-            Holdout {
-                albedo: config::ColorInput::Color { color },
-            } => Self::Holdout {
-                albedo: color.into(),
+            Holdout { albedo } => Self::Holdout {
+                albedo: albedo.try_into()?,
             },
-            _ => todo!(),
-        }
+            Emissive {
+                color,
+                strength,
+                base,
+            } => Self::Emissive {
+                color: color.try_into()?,
+                strength,
+                base: base.map(|b| boxed(*b)).transpose()?,
+            },
+            Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+            } => Self::Principled {
+                base_color: base_color.try_into()?,
+                metallic,
+                roughness,
+                specular,
+            },
+            Volume {
+                absorption,
+                scattering,
+                anisotropy,
+            } => Self::Volume {
+                medium: Medium {
+                    absorption: absorption.into(),
+                    scattering: scattering.into(),
+                    anisotropy,
+                },
+            },
+            Mix { a, b, factor } => Self::Mix {
+                a: boxed(*a)?,
+                b: boxed(*b)?,
+                factor: match factor {
+                    config::Factor::Constant(f) => Texture::Constant(Color::from_value(f)),
+                    config::Factor::Texture(texture) => texture.try_into()?,
+                },
+            },
+            Coated {
+                base,
+                coat_ior,
+                coat_roughness,
+            } => Self::Coated {
+                base: boxed(*base)?,
+                ior: coat_ior,
+                roughness: coat_roughness,
+            },
+        })
     }
 }
 
@@ -52,84 +145,522 @@ impl From<Material> for config::Material {
     fn from(m: Material) -> Self {
         match m {
             Material::Holdout { albedo } => Self::Holdout {
-                albedo: config::ColorInput::Color {
-                    color: albedo.into(),
-                },
+                albedo: albedo.into(),
             },
             Material::Lambert { albedo } => Self::Lambert {
-                albedo: config::ColorInput::Color {
-                    color: albedo.into(),
-                },
+                albedo: albedo.into(),
             },
             Material::Metal { albedo, fuzz } => Self::Metal {
-                albedo: config::ColorInput::Color {
-                    color: albedo.into(),
-                },
+                albedo: albedo.into(),
                 fuzz,
             },
-            Material::Dielectric { transmittance, ior } => Self::Dielectric {
-                albedo: config::ColorInput::Color {
-                    color: transmittance.into(),
+            Material::Dielectric {
+                transmittance,
+                ior,
+                roughness,
+            } => Self::Dielectric {
+                albedo: transmittance.into(),
+                ior,
+                roughness,
+            },
+            Material::Emissive {
+                color,
+                strength,
+                base,
+            } => Self::Emissive {
+                color: color.into(),
+                strength,
+                base: base.map(|b| Box::new((*b).into())),
+            },
+            Material::Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+            } => Self::Principled {
+                base_color: base_color.into(),
+                metallic,
+                roughness,
+                specular,
+            },
+            Material::Volume { medium } => Self::Volume {
+                absorption: medium.absorption.into(),
+                scattering: medium.scattering.into(),
+                anisotropy: medium.anisotropy,
+            },
+            Material::Mix { a, b, factor } => Self::Mix {
+                a: Box::new((*a).into()),
+                b: Box::new((*b).into()),
+                factor: match factor {
+                    Texture::Constant(c) if c.x == c.y && c.y == c.z => {
+                        config::Factor::Constant(c.x)
+                    }
+                    texture => config::Factor::Texture(texture.into()),
                 },
+            },
+            Material::Coated {
+                base,
                 ior,
+                roughness,
+            } => Self::Coated {
+                base: Box::new((*base).into()),
+                coat_ior: ior,
+                coat_roughness: roughness,
             },
         }
     }
 }
 
 #[derive(Copy, Clone, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Bounce {
-    Sky(Ray),
     Stop(Color),
+    /// Direction sampled from the density given by `pdf`.
     Bounce(Color, Ray),
+    /// Direction from a lobe which `eval` and `pdf` don't account for, such as a mirror.
+    Specular(Color, Ray),
 }
 
 impl Material {
     #[cfg(not(feature = "debug_normals"))]
-    pub fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Bounce {
-        match *self {
-            Self::Holdout { albedo } => Bounce::Stop(albedo),
+    pub fn scatter(
+        &self,
+        rng: &mut SampleRng,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelength: f64,
+    ) -> Bounce {
+        match self {
+            Self::Holdout { albedo } => Bounce::Stop(albedo.sample(&hit.uv)),
             Self::Lambert { albedo } => {
-                let mut rng = thread_rng();
-                let dir: V3 = hit.normal + random_vector(&mut rng);
+                let dir: V3 = hit.normal + random_vector(rng);
                 let dir = if near_zero(dir) { hit.normal } else { dir };
 
-                Bounce::Bounce(albedo, Ray::new(hit.point, dir))
+                Bounce::Bounce(albedo.sample(&hit.uv), Ray::new(hit.point, dir))
             }
             Self::Metal { albedo, fuzz } => {
-                let mut rng = thread_rng();
                 let reflected = reflect(ray.dir().normalize(), hit.normal);
-                let scattered = Ray::new(hit.point, reflected + fuzz * random_vector(&mut rng));
+                let scattered = Ray::new(hit.point, reflected + *fuzz * random_vector(rng));
+                let albedo = albedo.sample(&hit.uv);
                 if scattered.dir().dot(hit.normal) > 0.0 {
-                    Bounce::Bounce(albedo, scattered)
+                    Bounce::Specular(albedo, scattered)
                 } else {
                     Bounce::Stop(albedo)
                 }
             }
-            Self::Dielectric { transmittance, ior } => {
-                let mut rng = thread_rng();
+            Self::Dielectric { ior, roughness, .. } => {
                 let distr = rand::distributions::Uniform::new(0.0, 1.0);
+                let ior = ior.at(wavelength);
                 let rratio = if hit.front_face { 1.0 / ior } else { ior };
                 let dir = ray.dir().normalize();
-                let cos_theta = (-dir).dot(hit.normal).min(1.0);
+                let (normal, facets) = if *roughness > 0.0 {
+                    let facets = Ggx::new(*roughness, hit.normal);
+                    let m = facets.sample_normal(rng.sample(distr), rng.sample(distr));
+                    (m, Some(facets))
+                } else {
+                    (hit.normal, None)
+                };
+                let cos_theta = (-dir).dot(normal).min(1.0);
                 let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
                 let cannot_refract = rratio * sin_theta > 1.0;
                 let refl = reflectance(cos_theta, rratio);
-                let new_dir = if cannot_refract || refl > rng.sample(distr) {
-                    reflect(dir, hit.normal)
+                let reflected = cannot_refract || refl > rng.sample(distr);
+                let new_dir = if reflected {
+                    reflect(dir, normal)
                 } else {
-                    refract(dir, hit.normal, rratio)
+                    refract(dir, normal, rratio)
+                };
+                // Sampling the facet normal and choosing between reflection and refraction with
+                // the Fresnel term leave only the masking term
+                let weight = match facets {
+                    Some(facets) => {
+                        let cos_o = -dir.dot(hit.normal);
+                        let cos_i = new_dir.dot(hit.normal);
+                        // The facet faces away from the ray, or sends it to the wrong side
+                        if cos_theta <= 0.0 || (cos_i > 0.0) != reflected {
+                            return Bounce::Stop(Color::zero());
+                        }
+                        facets.masking(cos_o) * facets.masking(cos_i.abs()) * cos_theta
+                            / (cos_o * normal.dot(hit.normal))
+                    }
+                    None => 1.0,
                 };
-                Bounce::Bounce(transmittance, Ray::new(hit.point, new_dir))
+                Bounce::Specular(Color::from_value(weight), Ray::new(hit.point, new_dir))
+            }
+            Self::Emissive { base, .. } => match base {
+                Some(base) => base.scatter(rng, ray, hit, wavelength),
+                None => Bounce::Stop(Color::zero()),
+            },
+            Self::Principled { .. } => {
+                let wo = -ray.dir().normalize();
+                let lobes = Microfacet::new(self, hit);
+                let distr = rand::distributions::Uniform::new(0.0, 1.0);
+                let dir = if rng.sample(distr) < lobes.specular_probability(wo) {
+                    let h = lobes
+                        .ggx
+                        .sample_normal(rng.sample(distr), rng.sample(distr));
+                    reflect(-wo, h)
+                } else {
+                    let dir = hit.normal + random_vector(rng);
+                    if near_zero(dir) {
+                        hit.normal
+                    } else {
+                        dir.normalize()
+                    }
+                };
+                let pdf = lobes.pdf(wo, dir);
+                if pdf > 0.0 {
+                    Bounce::Bounce(lobes.eval(wo, dir) / pdf, Ray::new(hit.point, dir))
+                } else {
+                    Bounce::Stop(Color::zero())
+                }
+            }
+            Self::Volume { .. } => {
+                Bounce::Specular(Color::new(1.0, 1.0, 1.0), Ray::new(hit.point, ray.dir()))
+            }
+            Self::Mix { a, b, factor } => {
+                let distr = rand::distributions::Uniform::new(0.0, 1.0);
+                if rng.sample(distr) < mix_factor(factor, hit) {
+                    b.scatter(rng, ray, hit, wavelength)
+                } else {
+                    a.scatter(rng, ray, hit, wavelength)
+                }
+            }
+            Self::Coated {
+                base,
+                ior,
+                roughness,
+            } => {
+                let distr = rand::distributions::Uniform::new(0.0, 1.0);
+                let wo = -ray.dir().normalize();
+                let cos_o = wo.dot(hit.normal);
+                // The coat reflects with the probability given by its Fresnel term, which
+                // cancels out of the weight of both lobes
+                let coat = reflectance(cos_o, *ior);
+                if rng.sample(distr) < coat {
+                    if *roughness == 0.0 {
+                        let dir = reflect(-wo, hit.normal);
+                        return Bounce::Specular(
+                            Color::new(1.0, 1.0, 1.0),
+                            Ray::new(hit.point, dir),
+                        );
+                    }
+                    let facets = Ggx::new(*roughness, hit.normal);
+                    let m = facets.sample_normal(rng.sample(distr), rng.sample(distr));
+                    let dir = reflect(-wo, m);
+                    let (cos_m, cos_i) = (wo.dot(m), dir.dot(hit.normal));
+                    if cos_m <= 0.0 || cos_i <= 0.0 {
+                        return Bounce::Stop(Color::zero());
+                    }
+                    let weight = reflectance(cos_m, *ior) / coat
+                        * facets.masking(cos_o)
+                        * facets.masking(cos_i)
+                        * cos_m
+                        / (cos_o * m.dot(hit.normal));
+                    return Bounce::Specular(Color::from_value(weight), Ray::new(hit.point, dir));
+                }
+                // Rays refracted by a dielectric base leave through the coat too
+                let out = |ray: &Ray| 1.0 - reflectance(ray.dir().dot(hit.normal).abs(), *ior);
+                match base.scatter(rng, ray, hit, wavelength) {
+                    Bounce::Bounce(color, ray) => Bounce::Bounce(color * out(&ray), ray),
+                    Bounce::Specular(color, ray) => Bounce::Specular(color * out(&ray), ray),
+                    Bounce::Stop(color) => Bounce::Stop(color),
+                }
+            }
+        }
+    }
+
+    /// Overall surface color at `hit`, regardless of lighting.
+    pub fn albedo(&self, hit: &HitRecord) -> Color {
+        match self {
+            Self::Holdout { albedo } | Self::Lambert { albedo } | Self::Metal { albedo, .. } => {
+                albedo.sample(&hit.uv)
+            }
+            Self::Dielectric { transmittance, .. } => transmittance.sample(&hit.uv),
+            Self::Emissive {
+                base: Some(base), ..
+            } => base.albedo(hit),
+            Self::Emissive { color, .. } => color.sample(&hit.uv),
+            Self::Principled { base_color, .. } => base_color.sample(&hit.uv),
+            // Fraction of the light which is scattered rather than absorbed
+            Self::Volume { medium } => {
+                let albedo = |s: f64, a: f64| if s > 0.0 { s / (s + a) } else { 0.0 };
+                let (s, a) = (medium.scattering, medium.absorption);
+                Color::new(albedo(s.x, a.x), albedo(s.y, a.y), albedo(s.z, a.z))
+            }
+            Self::Mix { a, b, factor } => {
+                let f = mix_factor(factor, hit);
+                a.albedo(hit) * (1.0 - f) + b.albedo(hit) * f
+            }
+            Self::Coated { base, .. } => base.albedo(hit),
+        }
+    }
+
+    /// Whether rays go through the surface unchanged, as it only marks the boundary of a medium.
+    pub fn is_boundary(&self) -> bool {
+        matches!(self, Self::Volume { .. })
+    }
+
+    /// Medium filling objects made of volumes, also under a coat or an emitter, or as the first
+    /// material of a mix holding one.
+    pub fn medium(&self) -> Option<&Medium> {
+        match self {
+            Self::Volume { medium } => Some(medium),
+            Self::Emissive {
+                base: Some(base), ..
+            }
+            | Self::Coated { base, .. } => base.medium(),
+            Self::Mix { a, b, .. } => a.medium().or_else(|| b.medium()),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the light let through per unit distance inside the dielectric at `hit`,
+    /// found like `medium`.
+    pub fn interior(&self, hit: &HitRecord) -> Option<Color> {
+        match self {
+            Self::Dielectric { transmittance, .. } => Some(transmittance.sample(&hit.uv)),
+            Self::Emissive {
+                base: Some(base), ..
+            }
+            | Self::Coated { base, .. } => base.interior(hit),
+            Self::Mix { a, b, .. } => a.interior(hit).or_else(|| b.interior(hit)),
+            _ => None,
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        match self {
+            Self::Emissive { .. } => true,
+            Self::Mix { a, b, .. } => a.is_emissive() || b.is_emissive(),
+            Self::Coated { base, .. } => base.is_emissive(),
+            _ => false,
+        }
+    }
+
+    /// BSDF times the cosine term for light arriving from `wi` and leaving towards `wo`, for
+    /// non-specular materials only; specular materials can't be sampled explicitly so they
+    /// return `None`.
+    pub fn eval(&self, hit: &HitRecord, wo: V3, wi: V3) -> Option<Color> {
+        match self {
+            Self::Lambert { albedo } => {
+                Some(albedo.sample(&hit.uv) * (hit.normal.dot(wi).max(0.0) / PI))
+            }
+            Self::Emissive {
+                base: Some(base), ..
+            } => base.eval(hit, wo, wi),
+            Self::Principled { .. } => {
+                Some(Microfacet::new(self, hit).eval(wo.normalize(), wi.normalize()))
             }
+            Self::Mix { a, b, factor } => {
+                let f = mix_factor(factor, hit);
+                match (a.eval(hit, wo, wi), b.eval(hit, wo, wi)) {
+                    (None, None) => None,
+                    (a, b) => Some(
+                        a.unwrap_or_else(Color::zero) * (1.0 - f)
+                            + b.unwrap_or_else(Color::zero) * f,
+                    ),
+                }
+            }
+            Self::Coated { base, ior, .. } => {
+                let (cos_o, cos_i) = (
+                    hit.normal.dot(wo.normalize()),
+                    hit.normal.dot(wi.normalize()),
+                );
+                let transmitted =
+                    (1.0 - reflectance(cos_o, *ior)) * (1.0 - reflectance(cos_i.abs(), *ior));
+                base.eval(hit, wo, wi).map(|f| f * transmitted)
+            }
+            _ => None,
+        }
+    }
+
+    /// Solid angle density with which `scatter` samples the direction `wi` when looking from
+    /// `wo`, for non-specular materials only.
+    pub fn pdf(&self, hit: &HitRecord, wo: V3, wi: V3) -> Option<f64> {
+        match self {
+            Self::Lambert { .. } => Some(hit.normal.dot(wi).max(0.0) / PI),
+            Self::Emissive {
+                base: Some(base), ..
+            } => base.pdf(hit, wo, wi),
+            Self::Principled { .. } => {
+                Some(Microfacet::new(self, hit).pdf(wo.normalize(), wi.normalize()))
+            }
+            Self::Mix { a, b, factor } => {
+                let f = mix_factor(factor, hit);
+                match (a.pdf(hit, wo, wi), b.pdf(hit, wo, wi)) {
+                    (None, None) => None,
+                    (a, b) => Some(a.unwrap_or(0.0) * (1.0 - f) + b.unwrap_or(0.0) * f),
+                }
+            }
+            Self::Coated { base, ior, .. } => {
+                let coat = reflectance(hit.normal.dot(wo.normalize()), *ior);
+                base.pdf(hit, wo, wi).map(|p| p * (1.0 - coat))
+            }
+            _ => None,
+        }
+    }
+
+    /// Radiance emitted by the surface towards the incoming ray. Only the front face emits.
+    pub fn emitted(&self, hit: &HitRecord) -> Color {
+        match self {
+            Self::Emissive {
+                color, strength, ..
+            } if hit.front_face => color.sample(&hit.uv) * *strength,
+            Self::Mix { a, b, factor } => {
+                let f = mix_factor(factor, hit);
+                a.emitted(hit) * (1.0 - f) + b.emitted(hit) * f
+            }
+            Self::Coated { base, .. } => base.emitted(hit),
+            _ => Color::zero(),
         }
     }
     #[cfg(feature = "debug_normals")]
-    pub fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Bounce {
+    pub fn scatter(
+        &self,
+        rng: &mut SampleRng,
+        ray: &Ray,
+        hit: &HitRecord,
+        wavelength: f64,
+    ) -> Bounce {
         Bounce::Stop(V3::new(0.5, 0.5, 0.5) + 0.5 * hit.normal)
     }
 }
 
+/// GGX distribution of microfacet normals around `normal`.
+#[derive(Copy, Clone)]
+struct Ggx {
+    normal: V3,
+    /// Width of the distribution, the square of the roughness
+    alpha: f64,
+}
+
+impl Ggx {
+    fn new(roughness: f64, normal: V3) -> Self {
+        Self {
+            normal,
+            // Perfectly smooth surfaces would need a Dirac lobe
+            alpha: (roughness * roughness).max(1e-3),
+        }
+    }
+
+    fn distribution(&self, cos_h: f64) -> f64 {
+        let a2 = self.alpha * self.alpha;
+        let d = cos_h * cos_h * (a2 - 1.0) + 1.0;
+        a2 / (PI * d * d)
+    }
+
+    /// Smith masking term of one direction.
+    fn masking(&self, cos: f64) -> f64 {
+        let a2 = self.alpha * self.alpha;
+        2.0 * cos / (cos + (a2 + (1.0 - a2) * cos * cos).sqrt())
+    }
+
+    /// Microfacet normal distributed according to D(m) cos(m).
+    fn sample_normal(&self, u1: f64, u2: f64) -> V3 {
+        let a2 = self.alpha * self.alpha;
+        let cos_theta = ((1.0 - u2) / (1.0 + (a2 - 1.0) * u2)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u1;
+        let (t, b) = orthonormal_basis(self.normal);
+        t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + self.normal * cos_theta
+    }
+}
+
+/// Lobes of a `Principled` material at one hit point. Directions are unit vectors pointing
+/// away from the surface.
+struct Microfacet {
+    normal: V3,
+    diffuse: Color,
+    /// Reflectance at normal incidence
+    f0: Color,
+    ggx: Ggx,
+}
+
+impl Microfacet {
+    fn new(material: &Material, hit: &HitRecord) -> Self {
+        match material {
+            Material::Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+            } => {
+                let base = base_color.sample(&hit.uv);
+                let dielectric = Color::new(1.0, 1.0, 1.0) * (0.08 * specular);
+                Self {
+                    normal: hit.normal,
+                    diffuse: base * (1.0 - metallic),
+                    f0: dielectric + (base - dielectric) * *metallic,
+                    ggx: Ggx::new(*roughness, hit.normal),
+                }
+            }
+            _ => unreachable!("not a principled material"),
+        }
+    }
+
+    fn fresnel(&self, cos: f64) -> Color {
+        let w = (1.0 - cos).max(0.0).powi(5);
+        self.f0 + (Color::new(1.0, 1.0, 1.0) - self.f0) * w
+    }
+
+    /// Chance of sampling the specular lobe rather than the diffuse one, from their rough
+    /// share of the reflected light.
+    fn specular_probability(&self, wo: V3) -> f64 {
+        let specular = luminance(self.fresnel(self.normal.dot(wo)));
+        let diffuse = luminance(self.diffuse);
+        if specular + diffuse > 0.0 {
+            (specular / (specular + diffuse)).max(0.1)
+        } else {
+            0.5
+        }
+    }
+
+    fn eval(&self, wo: V3, wi: V3) -> Color {
+        let (cos_o, cos_i) = (self.normal.dot(wo), self.normal.dot(wi));
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Color::zero();
+        }
+        let h = (wo + wi).normalize();
+        let fresnel = self.fresnel(wo.dot(h));
+        let specular = fresnel
+            * (self.ggx.distribution(self.normal.dot(h))
+                * self.ggx.masking(cos_o)
+                * self.ggx.masking(cos_i)
+                / (4.0 * cos_o * cos_i));
+        // Light reflected by the specular layer on the way in or out doesn't reach the base
+        let one = Color::new(1.0, 1.0, 1.0);
+        let diffuse = self
+            .diffuse
+            .mul_element_wise(one - self.fresnel(cos_i))
+            .mul_element_wise(one - self.fresnel(cos_o))
+            / PI;
+        (diffuse + specular) * cos_i
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        let cos_i = self.normal.dot(wi);
+        if cos_i <= 0.0 || self.normal.dot(wo) <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).normalize();
+        let specular =
+            self.ggx.distribution(self.normal.dot(h)) * self.normal.dot(h) / (4.0 * wo.dot(h));
+        let p = self.specular_probability(wo);
+        p * specular + (1.0 - p) * cos_i / PI
+    }
+}
+
+/// Probability of picking `b` in a `Mix` material.
+fn mix_factor(factor: &Texture, hit: &HitRecord) -> f64 {
+    let f = factor.sample(&hit.uv);
+    ((f.x + f.y + f.z) / 3.0).clamp(0.0, 1.0)
+}
+
+fn luminance(c: Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
 fn near_zero(v: V3) -> bool {
     let v = v.map(|x| x.abs() < 1e-8);
     v.x && v.y && v.z
@@ -151,3 +682,308 @@ fn reflectance(cosine: f64, idx: f64) -> f64 {
     let r0 = r0 * r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::TexCoords;
+    use crate::utils::sample_rng;
+    use cgmath::{Point3, Vector2};
+
+    fn principled(base_color: Color, metallic: f64, roughness: f64) -> Material {
+        Material::Principled {
+            base_color: Texture::Constant(base_color),
+            metallic,
+            roughness,
+            specular: 0.5,
+        }
+    }
+
+    fn hit(material: &Material) -> HitRecord<'_> {
+        HitRecord {
+            point: Point3::new(0.0, 0.0, 0.0),
+            normal: V3::unit_z(),
+            geometric_normal: V3::unit_z(),
+            t: 1.0,
+            front_face: true,
+            uv: TexCoords::Uv(Vector2::new(0.0, 0.0)),
+            material,
+            object_id: 0,
+            leaf: 0,
+            material_id: 0,
+        }
+    }
+
+    /// Average throughput of `scatter` for a ray arriving from `wo`, split between the light
+    /// sent back above the surface and the light transmitted below it.
+    fn scattered(material: &Material, wo: V3, samples: u32) -> (Color, Color) {
+        let mut rng = sample_rng(7, 0, 0, 0);
+        let hit = hit(material);
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0) + wo, -wo);
+        let (reflected, transmitted) = (0..samples).fold(
+            (Color::zero(), Color::zero()),
+            |(reflected, transmitted), _| match material.scatter(&mut rng, &ray, &hit, 550.0) {
+                Bounce::Bounce(color, ray) | Bounce::Specular(color, ray) if ray.dir().z < 0.0 => {
+                    (reflected, transmitted + color)
+                }
+                Bounce::Bounce(color, _) | Bounce::Specular(color, _) | Bounce::Stop(color) => {
+                    (reflected + color, transmitted)
+                }
+            },
+        );
+        (
+            reflected / f64::from(samples),
+            transmitted / f64::from(samples),
+        )
+    }
+
+    /// Fractions of the light arriving at incidence cosines 1, 0.5 and 0.1 on a white `material`
+    /// which it reflects and transmits, along with the cosine. Together they make at most all
+    /// the light, and at least `min`, as rough surfaces lose light between microfacets.
+    fn assert_conserves_energy(material: &Material, min: f64) -> Vec<(f64, f64, f64)> {
+        [1.0_f64, 0.5, 0.1]
+            .iter()
+            .map(|&cos| {
+                let wo = V3::new((1.0 - cos * cos).sqrt(), 0.0, cos);
+                let (r, t) = scattered(material, wo, 20_000);
+                assert!(
+                    r.x + t.x <= 1.02 && r.x + t.x >= min,
+                    "{:?}, cos {}: {} + {}",
+                    material,
+                    cos,
+                    r.x,
+                    t.x
+                );
+                (cos, r.x, t.x)
+            })
+            .collect()
+    }
+
+    /// A white surface reflects at most all the light it receives, and a white metal smooth
+    /// enough for masking not to matter reflects all of it.
+    #[test]
+    fn principled_conserves_energy() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        for &(metallic, roughness) in &[(0.0, 0.0), (0.0, 0.5), (1.0, 1.0)] {
+            assert_conserves_energy(&principled(white, metallic, roughness), 0.25);
+        }
+        for (cos, reflected, _) in assert_conserves_energy(&principled(white, 1.0, 0.1), 0.0) {
+            assert!((reflected - 1.0).abs() < 0.01, "cos {}: {}", cos, reflected);
+        }
+    }
+
+    /// Sampling must agree with `eval` and `pdf`, which are used for light sampling: the
+    /// throughput of sampled directions matches the integral of `eval`. `specular` is the part
+    /// of the throughput coming from lobes which `eval` doesn't cover.
+    fn assert_sampling_matches_eval(material: &Material, specular: Color) {
+        let hit = hit(material);
+        let wo = V3::new(0.6, 0.0, 0.8);
+        let mut rng = sample_rng(3, 0, 0, 0);
+        let samples = 200_000;
+        let (mut density, mut integral) = (0.0, Color::zero());
+        for _ in 0..samples {
+            // Uniform directions over the sphere
+            let wi = random_vector(&mut rng);
+            density += material.pdf(&hit, wo, wi).unwrap() * 4.0 * PI;
+            integral += material.eval(&hit, wo, wi).unwrap() * 4.0 * PI;
+        }
+        let density = density / f64::from(samples);
+        let integral = integral / f64::from(samples) + specular;
+        // Microfacet samples may point below the surface, and stop the path
+        assert!(density < 1.01 && density > 0.5, "{}", density);
+        let (reflected, transmitted) = scattered(material, wo, samples);
+        let sampled = reflected + transmitted;
+        for (a, b) in [
+            (integral.x, sampled.x),
+            (integral.y, sampled.y),
+            (integral.z, sampled.z),
+        ]
+        .iter()
+        {
+            assert!((a - b).abs() < 0.02, "{:?} {:?}", integral, sampled);
+        }
+    }
+
+    #[test]
+    fn principled_sampling_matches_eval() {
+        let material = principled(Color::new(0.8, 0.3, 0.1), 0.3, 0.5);
+        assert_sampling_matches_eval(&material, Color::zero());
+    }
+
+    #[test]
+    fn mix_sampling_matches_eval() {
+        let lambert = Material::Lambert {
+            albedo: Texture::Constant(Color::new(0.2, 0.9, 0.4)),
+        };
+        let mix = |b: Material| Material::Mix {
+            a: Box::new(lambert.clone()),
+            b: Box::new(b),
+            factor: Texture::Constant(Color::from_value(0.3)),
+        };
+        let principled = principled(Color::new(0.8, 0.3, 0.1), 1.0, 0.4);
+        assert_sampling_matches_eval(&mix(principled), Color::zero());
+        // Mirror reflections are left out of `eval`, and only found by sampling
+        let mirror = Material::Metal {
+            albedo: Texture::Constant(Color::new(0.5, 0.5, 0.5)),
+            fuzz: 0.0,
+        };
+        assert_sampling_matches_eval(&mix(mirror), Color::from_value(0.3 * 0.5));
+    }
+
+    #[test]
+    fn coated_sampling_matches_eval() {
+        let base = principled(Color::new(0.8, 0.3, 0.1), 0.0, 0.7);
+        let coated = |roughness| Material::Coated {
+            base: Box::new(base.clone()),
+            ior: 1.5,
+            roughness,
+        };
+        // The smooth coat is a mirror reflecting the Fresnel reflectance
+        let coat = reflectance(0.8, 1.5);
+        assert_sampling_matches_eval(&coated(0.0), Color::from_value(coat));
+    }
+
+    /// A coat can only take light away from a white base; rough coats lose some of what they
+    /// reflect to masking. Over a black base, a smooth coat reflects its Fresnel reflectance.
+    #[test]
+    fn coated_conserves_energy() {
+        let coated = |albedo, roughness| Material::Coated {
+            base: Box::new(Material::Lambert {
+                albedo: Texture::Constant(Color::from_value(albedo)),
+            }),
+            ior: 1.5,
+            roughness,
+        };
+        for &roughness in &[0.0, 0.3, 1.0] {
+            assert_conserves_energy(&coated(1.0, roughness), 0.25);
+        }
+        for (cos, reflected, _) in assert_conserves_energy(&coated(0.0, 0.0), 0.0) {
+            let fresnel = reflectance(cos, 1.5);
+            assert!(
+                (reflected - fresnel).abs() < 0.015,
+                "cos {}: {}, expected {}",
+                cos,
+                reflected,
+                fresnel
+            );
+        }
+    }
+
+    /// Light refracted by glass under a coat goes through the coat on the way in and out.
+    #[test]
+    fn coated_dielectric_transmits() {
+        let material = Material::Coated {
+            base: Box::new(Material::Dielectric {
+                transmittance: Texture::Constant(Color::new(1.0, 1.0, 1.0)),
+                ior: config::Ior::Constant(1.5),
+                roughness: 0.0,
+            }),
+            ior: 1.5,
+            roughness: 0.0,
+        };
+        let (_, transmitted) = scattered(&material, V3::unit_z(), 20_000);
+        let expected = (1.0 - reflectance(1.0, 1.5)).powi(3);
+        assert!(
+            (transmitted.x - expected).abs() < 0.015,
+            "{}, expected {}",
+            transmitted.x,
+            expected
+        );
+    }
+
+    /// Rays going through wrapped volumes and dielectrics enter them.
+    #[test]
+    fn wrapped_interiors() {
+        let medium = Medium {
+            absorption: Color::new(0.1, 0.2, 0.3),
+            scattering: Color::new(1.0, 1.0, 1.0),
+            anisotropy: 0.0,
+        };
+        let glass = Material::Dielectric {
+            transmittance: Texture::Constant(Color::new(0.5, 1.0, 1.0)),
+            ior: config::Ior::Constant(1.5),
+            roughness: 0.0,
+        };
+        let lambert = Material::Lambert {
+            albedo: Texture::Constant(Color::new(0.5, 0.5, 0.5)),
+        };
+        let wrappers = |base: Material| {
+            vec![
+                Material::Coated {
+                    base: Box::new(base.clone()),
+                    ior: 1.5,
+                    roughness: 0.0,
+                },
+                Material::Emissive {
+                    color: Texture::Constant(Color::new(1.0, 1.0, 1.0)),
+                    strength: 1.0,
+                    base: Some(Box::new(base.clone())),
+                },
+                Material::Mix {
+                    a: Box::new(lambert.clone()),
+                    b: Box::new(base),
+                    factor: Texture::Constant(Color::from_value(0.5)),
+                },
+            ]
+        };
+        for material in wrappers(Material::Volume { medium }) {
+            assert_eq!(material.medium(), Some(&medium));
+            assert!(!material.is_boundary());
+        }
+        for material in wrappers(glass) {
+            let interior = material.interior(&hit(&material));
+            assert_eq!(interior, Some(Color::new(0.5, 1.0, 1.0)));
+        }
+    }
+
+    #[test]
+    fn dielectric_interior() {
+        let dielectric = |ior| Material::Dielectric {
+            transmittance: Texture::Constant(Color::new(0.5, 1.0, 1.0)),
+            ior: config::Ior::Constant(ior),
+            roughness: 0.0,
+        };
+        // Also for bubbles, less dense than the material around them
+        for &ior in &[1.5, 1.0 / 1.5] {
+            let material = dielectric(ior);
+            let interior = material.interior(&hit(&material));
+            assert_eq!(interior, Some(Color::new(0.5, 1.0, 1.0)));
+        }
+        let glass = dielectric(1.5);
+        // Absorption depends on the path, not on the interface
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0), -V3::unit_z());
+        match glass.scatter(&mut sample_rng(1, 0, 0, 0), &ray, &hit(&glass), 550.0) {
+            Bounce::Specular(color, _) => assert_eq!(color, Color::new(1.0, 1.0, 1.0)),
+            _ => panic!("smooth glass must refract or reflect"),
+        }
+    }
+
+    /// Light is either reflected or transmitted, less what masking between microfacets loses.
+    /// Smooth and nearly smooth glass reflect the Fresnel reflectance and transmit the rest.
+    #[test]
+    fn rough_dielectric_conserves_energy() {
+        let glass = |roughness| Material::Dielectric {
+            transmittance: Texture::Constant(Color::new(1.0, 1.0, 1.0)),
+            ior: config::Ior::Constant(1.5),
+            roughness,
+        };
+        for &roughness in &[0.5, 1.0] {
+            assert_conserves_energy(&glass(roughness), 0.25);
+        }
+        for &roughness in &[0.0, 0.1] {
+            for (cos, reflected, _) in assert_conserves_energy(&glass(roughness), 0.99) {
+                let fresnel = reflectance(cos, 1.5);
+                assert!(
+                    (reflected - fresnel).abs() < 0.015,
+                    "roughness {}, cos {}: {}, expected {}",
+                    roughness,
+                    cos,
+                    reflected,
+                    fresnel
+                );
+            }
+        }
+        // The reflectance at normal incidence is ((n - 1) / (n + 1))²
+        assert!((reflectance(1.0, 1.5) - 0.04).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,200 @@
+use cgmath::{ElementWise, InnerSpace, Zero};
+use rand::Rng;
+
+use crate::utils::{orthonormal_basis, SampleRng};
+use crate::{config, Color, V3};
+use std::f64::consts::PI;
+
+/// Homogeneous participating medium, such as fog or smoke. Coefficients are per unit distance
+/// and per color channel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Medium {
+    pub absorption: Color,
+    pub scattering: Color,
+    /// Henyey-Greenstein asymmetry, from -1 for back scattering to 1 for forward scattering
+    pub anisotropy: f64,
+}
+
+/// Medium filling the whole scene.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Atmosphere {
+    pub medium: Medium,
+    /// Distance after which rays which don't hit anything leave the atmosphere
+    pub distance: f64,
+}
+
+impl From<config::Atmosphere> for Atmosphere {
+    fn from(a: config::Atmosphere) -> Self {
+        Self {
+            medium: Medium {
+                absorption: a.absorption.into(),
+                scattering: a.scattering.into(),
+                anisotropy: a.anisotropy,
+            },
+            distance: a.distance,
+        }
+    }
+}
+
+impl From<Atmosphere> for config::Atmosphere {
+    fn from(a: Atmosphere) -> Self {
+        Self {
+            absorption: a.medium.absorption.into(),
+            scattering: a.medium.scattering.into(),
+            anisotropy: a.medium.anisotropy,
+            distance: a.distance,
+        }
+    }
+}
+
+/// Outcome of sampling the distance a ray travels through a medium.
+#[derive(Copy, Clone, Debug)]
+pub enum Interaction {
+    /// The ray scatters after `t`.
+    Scatter { t: f64, weight: Color },
+    /// The ray reaches the end of the segment.
+    Pass { weight: Color },
+}
+
+impl Medium {
+    fn extinction(&self) -> Color {
+        self.absorption + self.scattering
+    }
+
+    /// Fraction of the light left after `distance`, which may be infinite.
+    pub fn transmittance(&self, distance: f64) -> Color {
+        self.extinction()
+            .map(|e| if e > 0.0 { (-e * distance).exp() } else { 1.0 })
+    }
+
+    /// Where a ray scatters along a segment of length `max`. The distance is sampled from the
+    /// transmittance of a random channel, and weighted by the average density of all channels.
+    pub fn sample_distance(&self, rng: &mut SampleRng, max: f64) -> Interaction {
+        if self.scattering.is_zero() {
+            return Interaction::Pass {
+                weight: self.transmittance(max),
+            };
+        }
+        let distr = rand::distributions::Uniform::new(0.0, 1.0);
+        let extinction = self.extinction();
+        let channel = extinction[rng.gen_range(0, 3)];
+        let u: f64 = rng.sample(distr);
+        let t = if channel > 0.0 {
+            -(1.0 - u).ln() / channel
+        } else {
+            f64::INFINITY
+        };
+        let average = |c: Color| (c.x + c.y + c.z) / 3.0;
+        if t < max {
+            let transmittance = self.transmittance(t);
+            let pdf = average(extinction.mul_element_wise(transmittance));
+            Interaction::Scatter {
+                t,
+                weight: self.scattering.mul_element_wise(transmittance) / pdf,
+            }
+        } else {
+            let transmittance = self.transmittance(max);
+            Interaction::Pass {
+                weight: transmittance / average(transmittance),
+            }
+        }
+    }
+
+    /// Density of scattering from the propagation direction `dir` into `wi`, both unit vectors.
+    pub fn phase(&self, dir: V3, wi: V3) -> f64 {
+        let g = self.anisotropy;
+        let denom = 1.0 + g * g - 2.0 * g * dir.dot(wi);
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    /// New direction for a ray propagating along `dir` and scattering, distributed according to
+    /// the phase function.
+    pub fn sample_phase(&self, rng: &mut SampleRng, dir: V3) -> V3 {
+        let distr = rand::distributions::Uniform::new(0.0, 1.0);
+        let (u1, u2) = (rng.sample(distr), rng.sample(distr));
+        let g = self.anisotropy;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let (t, b) = orthonormal_basis(dir);
+        t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + dir * cos_theta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{random_vector, sample_rng};
+
+    #[test]
+    fn phase_function() {
+        let mut rng = sample_rng(5, 0, 0, 0);
+        let dir = V3::new(0.0, 0.6, 0.8);
+        for &anisotropy in &[-0.7, 0.0, 0.5, 0.9] {
+            let medium = Medium {
+                absorption: Color::zero(),
+                scattering: Color::new(1.0, 1.0, 1.0),
+                anisotropy,
+            };
+            // The density integrates to 1 over the sphere
+            let samples = 200_000;
+            let total: f64 = (0..samples)
+                .map(|_| medium.phase(dir, random_vector(&mut rng)) * 4.0 * PI)
+                .sum();
+            let total = total / f64::from(samples);
+            assert!((total - 1.0).abs() < 0.05, "g {}: {}", anisotropy, total);
+            // Sampled directions have the mean cosine of the distribution
+            let mean: f64 = (0..samples)
+                .map(|_| medium.sample_phase(&mut rng, dir).dot(dir))
+                .sum();
+            let mean = mean / f64::from(samples);
+            assert!(
+                (mean - anisotropy).abs() < 0.01,
+                "g {}: {}",
+                anisotropy,
+                mean
+            );
+        }
+    }
+
+    /// Weighted distance samples estimate the transmittance of the segment.
+    #[test]
+    fn distance_sampling() {
+        let medium = Medium {
+            absorption: Color::new(0.1, 0.5, 0.0),
+            scattering: Color::new(0.5, 0.2, 0.0),
+            anisotropy: 0.0,
+        };
+        let mut rng = sample_rng(9, 0, 0, 0);
+        let samples = 100_000;
+        let (mut passed, mut scattered) = (Color::zero(), Color::zero());
+        for _ in 0..samples {
+            match medium.sample_distance(&mut rng, 2.0) {
+                Interaction::Pass { weight } => passed += weight,
+                // Weights of scattering events integrate the scattered fraction of the light
+                Interaction::Scatter { t, weight } => {
+                    assert!(t < 2.0);
+                    scattered += weight;
+                }
+            }
+        }
+        let (passed, scattered) = (passed / samples as f64, scattered / samples as f64);
+        let expected = medium.transmittance(2.0);
+        for c in 0..3 {
+            assert!((passed[c] - expected[c]).abs() < 0.01, "{:?}", passed);
+            // ∫ σs e^(-σt t) dt over [0, 2]
+            let e = medium.extinction()[c];
+            let expected = if e > 0.0 {
+                medium.scattering[c] / e * (1.0 - (-e * 2.0).exp())
+            } else {
+                0.0
+            };
+            assert!((scattered[c] - expected).abs() < 0.01, "{:?}", scattered);
+        }
+    }
+}
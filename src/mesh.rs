@@ -0,0 +1,259 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use cgmath::{InnerSpace, Point3, Vector2};
+
+use crate::{aabb::Aabb, bvh::Bvh, ray::Ray, traits::Bounded, P3, V3};
+
+type V2 = Vector2<f64>;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Triangle {
+    positions: [P3; 3],
+    normals: Option<[V3; 3]>,
+    uvs: Option<[V2; 3]>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TriangleHit {
+    pub t: f64,
+    pub normal: V3,
+    /// Normal of the plane of the triangle, whatever the vertex normals
+    pub geometric_normal: V3,
+    pub uv: V2,
+}
+
+impl Triangle {
+    pub fn positions(&self) -> [P3; 3] {
+        self.positions
+    }
+
+    /// Möller-Trumbore intersection. The returned normal is interpolated from the vertex
+    /// normals when the mesh provides them, and is the geometric normal otherwise.
+    pub fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<TriangleHit> {
+        let [p0, p1, p2] = self.positions;
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let pvec = ray.dir().cross(e2);
+        let det = e1.dot(pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let tvec = ray.pos() - p0;
+        let u = tvec.dot(pvec) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let qvec = tvec.cross(e1);
+        let v = ray.dir().dot(qvec) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = e2.dot(qvec) * inv_det;
+        if !(tmin..=tmax).contains(&t) {
+            return None;
+        }
+
+        let w = 1.0 - u - v;
+        let geometric_normal = e1.cross(e2).normalize();
+        let normal = match self.normals {
+            Some([n0, n1, n2]) => (w * n0 + u * n1 + v * n2).normalize(),
+            None => geometric_normal,
+        };
+        let uv = match self.uvs {
+            Some([uv0, uv1, uv2]) => w * uv0 + u * uv1 + v * uv2,
+            None => V2::new(u, v),
+        };
+        Some(TriangleHit {
+            t,
+            normal,
+            geometric_normal,
+            uv,
+        })
+    }
+}
+
+impl Bounded for Triangle {
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [p0, p1, p2] = self.positions;
+        Some(
+            Aabb::new(p0, p0)
+                .union(&Aabb::new(p1, p1))
+                .union(&Aabb::new(p2, p2)),
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub filename: PathBuf,
+    triangles: Bvh<Triangle>,
+}
+
+impl Mesh {
+    pub fn load<P: AsRef<Path>>(filename: P) -> io::Result<Self> {
+        let filename = filename.as_ref();
+        let triangles = parse_obj(BufReader::new(File::open(filename)?))?;
+        Ok(Self {
+            filename: filename.to_path_buf(),
+            triangles: Bvh::new(triangles),
+        })
+    }
+
+    pub fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<TriangleHit> {
+        self.triangles.closest_hit(
+            ray,
+            tmin,
+            tmax,
+            |tri, tmin, tmax| tri.hit(ray, tmin, tmax),
+            |h| h.t,
+        )
+    }
+
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.triangles.bounding_box()
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = &Triangle> {
+        self.triangles.iter()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct FaceVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+fn invalid_data(line: usize, msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, msg),
+    )
+}
+
+fn parse_floats<'a, I: Iterator<Item = &'a str>>(
+    line: usize,
+    values: I,
+    count: usize,
+) -> io::Result<Vec<f64>> {
+    let values = values
+        .take(count)
+        .map(|v| v.parse::<f64>().map_err(|e| invalid_data(line, e)))
+        .collect::<io::Result<Vec<_>>>()?;
+    if values.len() < count {
+        Err(invalid_data(
+            line,
+            format!("expected {} values, got {}", count, values.len()),
+        ))
+    } else {
+        Ok(values)
+    }
+}
+
+/// Resolve a 1-based, possibly negative (relative to the end) OBJ index.
+fn resolve_index(line: usize, index: &str, len: usize) -> io::Result<usize> {
+    let i = index.parse::<isize>().map_err(|e| invalid_data(line, e))?;
+    let resolved = if i > 0 {
+        i as usize - 1
+    } else if i < 0 && (-i) as usize <= len {
+        (len as isize + i) as usize
+    } else {
+        return Err(invalid_data(line, format!("invalid index {}", i)));
+    };
+    if resolved < len {
+        Ok(resolved)
+    } else {
+        Err(invalid_data(line, format!("index {} out of range", i)))
+    }
+}
+
+fn parse_face_vertex(
+    line: usize,
+    s: &str,
+    positions: usize,
+    uvs: usize,
+    normals: usize,
+) -> io::Result<FaceVertex> {
+    let mut parts = s.split('/');
+    let position = resolve_index(line, parts.next().unwrap_or(""), positions)?;
+    let uv = match parts.next() {
+        Some("") | None => None,
+        Some(i) => Some(resolve_index(line, i, uvs)?),
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(i) => Some(resolve_index(line, i, normals)?),
+    };
+    Ok(FaceVertex {
+        position,
+        uv,
+        normal,
+    })
+}
+
+/// Parse the geometry of a Wavefront OBJ file into triangles. Polygons are triangulated as fans,
+/// and statements other than vertex data and faces (groups, materials, ...) are ignored.
+pub fn parse_obj<R: BufRead>(reader: R) -> io::Result<Vec<Triangle>> {
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut triangles = vec![];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let lineno = i + 1;
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let v = parse_floats(lineno, tokens, 3)?;
+                positions.push(Point3::new(v[0], v[1], v[2]));
+            }
+            Some("vn") => {
+                let v = parse_floats(lineno, tokens, 3)?;
+                normals.push(V3::new(v[0], v[1], v[2]).normalize());
+            }
+            Some("vt") => {
+                let v = parse_floats(lineno, tokens, 2)?;
+                uvs.push(V2::new(v[0], v[1]));
+            }
+            Some("f") => {
+                let face = tokens
+                    .map(|s| {
+                        parse_face_vertex(lineno, s, positions.len(), uvs.len(), normals.len())
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                if face.len() < 3 {
+                    return Err(invalid_data(lineno, "face with less than 3 vertices"));
+                }
+                for k in 1..face.len() - 1 {
+                    let fv = [face[0], face[k], face[k + 1]];
+                    let normals = if fv.iter().all(|v| v.normal.is_some()) {
+                        Some(fv.map(|v| normals[v.normal.unwrap()]))
+                    } else {
+                        None
+                    };
+                    let uvs = if fv.iter().all(|v| v.uv.is_some()) {
+                        Some(fv.map(|v| uvs[v.uv.unwrap()]))
+                    } else {
+                        None
+                    };
+                    triangles.push(Triangle {
+                        positions: fv.map(|v| positions[v.position]),
+                        normals,
+                        uvs,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(triangles)
+}
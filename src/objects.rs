@@ -1,111 +1,450 @@
-use std::f64::EPSILON;
-
 use crate::{
+    aabb::Aabb,
+    bvh::Bvh,
     config,
+    light::Light,
     material::Material,
+    mesh::Mesh,
     ray::Ray,
     sdf::SDF,
-    traits::{HitRecord, Hittable},
+    texture::TexCoords,
+    traits::{Bounded, HitRecord, Hittable},
+    utils::orthonormal_basis,
     P3, V3,
 };
-use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Transform, Transform3, Vector3};
-use std::cell::Cell;
+use cgmath::{
+    Deg, EuclideanSpace, InnerSpace, Matrix, Matrix4, SquareMatrix, Transform, Vector2, Vector3,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::f64::consts::PI;
 use std::ops::Neg;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ObjectData {
     Sphere { radius: f64 },
     Plane { normal: V3 },
     SDF { sdf: SDF },
+    Mesh { mesh: Mesh },
+    Group { children: Bvh<Object> },
 }
 
-#[derive(Debug)]
+/// Placement of some geometry in the scene. The geometry and the material are shared with
+/// the other instances of the same object.
+#[derive(Clone, Debug)]
 pub struct Object {
-    transform: Matrix4<f64>,
-    material: Material,
-    odata: ObjectData,
+    id: usize,
+    material_id: usize,
+    local_to_world: Matrix4<f64>,
+    world_to_local: Matrix4<f64>,
+    /// Material of the surface. Groups have none, unless it replaces those of their children.
+    material: Option<Arc<Material>>,
+    odata: Arc<ObjectData>,
+}
+
+/// Distance to the surface under which sphere tracing reports a hit
+const SDF_HIT_DIST: f64 = 1e-7;
+const SDF_MAX_STEPS: usize = 1000;
+
+/// Intersection in object space, before being brought back to world space.
+#[derive(Copy, Clone, Debug)]
+struct LocalHit {
+    t: f64,
+    /// Outward normal in object space, not necessarily normalized
+    normal: V3,
+    /// Normal of the triangle that was hit, for meshes with vertex normals
+    geometric_normal: Option<V3>,
+    uv: TexCoords,
+}
+
+fn transform_matrix(pos: [f64; 3], transform: Option<config::Transform>) -> Matrix4<f64> {
+    let components = match transform {
+        None => Matrix4::identity(),
+        Some(config::Transform::Matrix { matrix }) => Matrix4::from(matrix).transpose(),
+        Some(config::Transform::Components {
+            translate,
+            rotate,
+            scale,
+        }) => {
+            let rotation = match rotate {
+                config::Rotation::Euler([x, y, z]) => {
+                    Matrix4::from_angle_z(Deg(z))
+                        * Matrix4::from_angle_y(Deg(y))
+                        * Matrix4::from_angle_x(Deg(x))
+                }
+                config::Rotation::AxisAngle { axis, angle } => {
+                    Matrix4::from_axis_angle(V3::from(axis).normalize(), Deg(angle))
+                }
+            };
+            Matrix4::from_translation(translate.into()) * rotation * scale_matrix(scale)
+        }
+    };
+    Matrix4::from_translation(pos.into()) * components
 }
 
-impl From<config::Object> for Object {
-    fn from(o: config::Object) -> Self {
-        match o {
+pub fn scale_matrix(scale: config::Scale) -> Matrix4<f64> {
+    match scale {
+        config::Scale::Uniform(s) => Matrix4::from_scale(s),
+        config::Scale::NonUniform([x, y, z]) => Matrix4::from_nonuniform_scale(x, y, z),
+    }
+}
+
+/// Inverse of `m`, unless its determinant is zero. `Matrix4::invert` compares the determinant
+/// to an absolute epsilon instead, which rejects transforms with small scales.
+pub fn invert(m: Matrix4<f64>) -> Option<Matrix4<f64>> {
+    let det = m.determinant();
+    if det == 0.0 || !det.is_finite() {
+        return None;
+    }
+    // Scaled so that its determinant is 1 or -1
+    let scale = det.abs().powf(-0.25);
+    let inverse = (m * scale).invert()? * scale;
+    let finite = (0..4).all(|i| (0..4).all(|j| inverse[i][j].is_finite()));
+    Some(inverse).filter(|_| finite)
+}
+
+impl Object {
+    pub fn new(
+        local_to_world: Matrix4<f64>,
+        material: Option<Arc<Material>>,
+        odata: Arc<ObjectData>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            id: 0,
+            material_id: 0,
+            local_to_world,
+            world_to_local: invert(local_to_world).ok_or("transform is not invertible")?,
+            material,
+            odata,
+        })
+    }
+}
+
+/// Build the objects of `world`. Objects with identical materials share them, and the
+/// instances of the same named object share its geometry.
+pub fn build_world(
+    world: Vec<config::Object>,
+    definitions: BTreeMap<String, config::Object>,
+) -> Result<Vec<Object>, String> {
+    let mut builder = Builder {
+        definitions,
+        ..Builder::default()
+    };
+    world
+        .into_iter()
+        .enumerate()
+        .map(|(i, o)| Ok(builder.build(o)?.with_id(i)))
+        .collect()
+}
+
+#[derive(Default)]
+struct Builder {
+    definitions: BTreeMap<String, config::Object>,
+    /// Named objects already built, in the space of their definition
+    built: HashMap<String, Object>,
+    /// Materials already built, by their configuration as YAML
+    materials: HashMap<String, (usize, Arc<Material>)>,
+    /// Named objects being built, to detect cycles
+    stack: Vec<String>,
+}
+
+impl Builder {
+    fn build(&mut self, o: config::Object) -> Result<Object, String> {
+        let (odata, pos, transform, material) = match o {
             config::Object::Sphere {
                 material,
                 pos,
+                transform,
                 radius,
-            } => Self {
-                transform: Matrix4::from_translation(pos.into()),
-                material: material.into(),
-                odata: ObjectData::Sphere { radius },
-            },
-            config::Object::SDF { pos, sdf, material } => Self {
-                transform: Matrix4::from_translation(pos.into()),
-                material: material.into(),
-                odata: ObjectData::SDF { sdf: sdf.into() },
-            },
+            } => (ObjectData::Sphere { radius }, pos, transform, material),
+            config::Object::SDF {
+                pos,
+                transform,
+                sdf,
+                material,
+            } => (
+                ObjectData::SDF { sdf: sdf.into() },
+                pos,
+                transform,
+                material,
+            ),
             config::Object::Plane {
                 material,
                 pos,
+                transform,
                 normal,
-            } => Self {
-                transform: Matrix4::from_translation(pos.into()),
-                material: material.into(),
-                odata: ObjectData::Plane {
-                    normal: normal.into(),
+            } => (
+                ObjectData::Plane {
+                    normal: V3::from(normal).normalize(),
                 },
-            },
+                pos,
+                transform,
+                material,
+            ),
+            config::Object::Mesh {
+                material,
+                pos,
+                transform,
+                filename,
+            } => (
+                ObjectData::Mesh {
+                    mesh: Mesh::load(&filename)
+                        .map_err(|e| format!("cannot load mesh {}: {}", filename.display(), e))?,
+                },
+                pos,
+                transform,
+                material,
+            ),
+            config::Object::Group {
+                pos,
+                transform,
+                children,
+            } => {
+                let mut leaves = 0;
+                let children = children
+                    .into_iter()
+                    .map(|c| {
+                        let child = self.build(c)?.with_id(leaves);
+                        leaves += child.leaves();
+                        Ok(child)
+                    })
+                    .collect::<Result<_, String>>()?;
+                return Object::new(
+                    transform_matrix(pos, transform),
+                    None,
+                    Arc::new(ObjectData::Group {
+                        children: Bvh::new(children),
+                    }),
+                );
+            }
+            config::Object::Instance {
+                pos,
+                transform,
+                of,
+                material_override,
+            } => {
+                let definition = self.definition(of)?;
+                let mut instance = Object::new(
+                    transform_matrix(pos, transform) * definition.local_to_world,
+                    definition.material,
+                    definition.odata,
+                )?
+                .with_material_id(definition.material_id);
+                if let Some(material) = material_override {
+                    let (material_id, material) = self.material(material)?;
+                    instance.material = Some(material);
+                    instance.material_id = material_id;
+                }
+                return Ok(instance);
+            }
+        };
+        let (material_id, material) = self.material(material)?;
+        Ok(Object::new(
+            transform_matrix(pos, transform),
+            Some(material),
+            Arc::new(odata),
+        )?
+        .with_material_id(material_id))
+    }
+
+    /// The named object `name`, built on first use.
+    fn definition(&mut self, name: String) -> Result<Object, String> {
+        if let Some(o) = self.built.get(&name) {
+            return Ok(o.clone());
         }
+        if self.stack.contains(&name) {
+            return Err(format!("object `{}` is an instance of itself", name));
+        }
+        let definition = self
+            .definitions
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| format!("unknown object `{}`", name))?;
+        self.stack.push(name.clone());
+        let built = self.build(definition);
+        self.stack.pop();
+        let built = built?;
+        self.built.insert(name, built.clone());
+        Ok(built)
+    }
+
+    fn material(&mut self, material: config::Material) -> Result<(usize, Arc<Material>), String> {
+        let key = serde_yaml::to_string(&material).unwrap();
+        if let Some(built) = self.materials.get(&key) {
+            return Ok(built.clone());
+        }
+        let built = (self.materials.len(), Arc::new(material.try_into()?));
+        self.materials.insert(key, built.clone());
+        Ok(built)
     }
 }
 
 impl From<Object> for config::Object {
     fn from(o: Object) -> config::Object {
-        let material = o.material.into();
-        let pos = o.transform.transform_point(P3::origin()).into();
-        match o.odata {
+        let shared_material = o.material.clone();
+        let material = o.material.map(|m| Arc::unwrap_or_clone(m).into());
+        let pos = o.local_to_world.transform_point(P3::origin());
+        let linear = Matrix4::from_translation(-pos.to_vec()) * o.local_to_world;
+        let transform = if linear == Matrix4::identity() {
+            None
+        } else {
+            Some(config::Transform::Matrix {
+                matrix: linear.transpose().into(),
+            })
+        };
+        let pos = pos.into();
+        // Only groups can be without a material
+        let surface = || material.clone().unwrap();
+        match Arc::unwrap_or_clone(o.odata) {
             ObjectData::Sphere { radius } => config::Object::Sphere {
                 radius,
-                material,
+                material: surface(),
                 pos,
+                transform,
             },
             ObjectData::Plane { normal } => config::Object::Plane {
                 normal: normal.into(),
-                material,
+                material: surface(),
                 pos,
+                transform,
             },
             ObjectData::SDF { sdf } => config::Object::SDF {
-                material,
+                material: surface(),
                 pos,
+                transform,
                 sdf: sdf.into(),
             },
+            ObjectData::Mesh { mesh } => config::Object::Mesh {
+                material: surface(),
+                pos,
+                transform,
+                filename: mesh.filename,
+            },
+            // The material of a group replaces those of its children
+            ObjectData::Group { children } => config::Object::Group {
+                pos,
+                transform,
+                children: children
+                    .iter()
+                    .map(|c| match &shared_material {
+                        Some(m) => Object {
+                            material: Some(m.clone()),
+                            ..c.clone()
+                        },
+                        None => c.clone(),
+                    })
+                    .map(Into::into)
+                    .collect(),
+            },
         }
     }
 }
 
-impl Hittable for Object {
-    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord> {
-        let local_ray = ray.transformed(&self.transform);
-        match &self.odata {
+impl Object {
+    /// Set the index of this object in the scene, which is reported in its hit records. Within
+    /// groups, it is the index of the first leaf of the object among those of the group.
+    pub fn with_id(self, id: usize) -> Self {
+        Self { id, ..self }
+    }
+
+    /// Set the index of the material of this object, which is reported in its hit records.
+    pub fn with_material_id(self, material_id: usize) -> Self {
+        Self {
+            material_id,
+            ..self
+        }
+    }
+
+    /// Light sources for next-event estimation, for the emissive surfaces of this object which
+    /// can be sampled, including those inside groups.
+    pub fn lights(&self) -> Vec<Light> {
+        let mut lights = vec![];
+        self.collect_lights(self.id, 0, &Matrix4::identity(), None, &mut lights);
+        lights
+    }
+
+    /// Lights of the leaves under this object, which is the leaf `leaf` of the top-level
+    /// object `object_id`, or its first leaf for groups. `material` overrides their own.
+    fn collect_lights(
+        &self,
+        object_id: usize,
+        leaf: usize,
+        parent_to_world: &Matrix4<f64>,
+        material: Option<&Arc<Material>>,
+        lights: &mut Vec<Light>,
+    ) {
+        let local_to_world = parent_to_world * self.local_to_world;
+        let material = material.or(self.material.as_ref());
+        if let ObjectData::Group { children } = &*self.odata {
+            for child in children.iter() {
+                // The ID of a child is the index of its first leaf within the group
+                let leaf = leaf + child.id;
+                child.collect_lights(object_id, leaf, &local_to_world, material, lights);
+            }
+            return;
+        }
+        if !material.is_some_and(|m| m.is_emissive()) {
+            return;
+        }
+        match &*self.odata {
+            ObjectData::Sphere { radius } => {
+                // Only spheres which stay spheres in world space can be sampled
+                let scales = [V3::unit_x(), V3::unit_y(), V3::unit_z()]
+                    .map(|v| local_to_world.transform_vector(v).magnitude());
+                if (scales[0] - scales[1]).abs() > 1e-9 || (scales[0] - scales[2]).abs() > 1e-9 {
+                    return;
+                }
+                lights.push(Light::Sphere {
+                    object_id,
+                    leaf,
+                    center: local_to_world.transform_point(P3::origin()),
+                    radius: radius * scales[0],
+                })
+            }
+            ObjectData::Mesh { mesh } => {
+                lights.push(Light::mesh(object_id, leaf, mesh, &local_to_world))
+            }
+            ObjectData::Plane { .. } | ObjectData::SDF { .. } | ObjectData::Group { .. } => {}
+        }
+    }
+
+    /// Number of leaf objects under this one: itself, unless it is a group.
+    fn leaves(&self) -> usize {
+        match &*self.odata {
+            ObjectData::Group { children } => children.iter().map(Object::leaves).sum(),
+            _ => 1,
+        }
+    }
+
+    /// Intersect a ray given in object space; `t` is expressed in units of the ray direction,
+    /// which need not be normalized, so it is the same as for the world-space ray.
+    fn hit_local(&self, local_ray: &Ray, tmin: f64, tmax: f64) -> Option<LocalHit> {
+        match &*self.odata {
             ObjectData::SDF { sdf } => {
-                let mut depth = tmin;
-                let mut pos = local_ray.pos();
-                for _ in 0..1000 {
-                    let dist = sdf.sdf(pos);
-                    if dist < EPSILON {
-                        return Some(HitRecord::from_hit(
-                            ray,
-                            sdf.sdf_d(pos),
-                            depth,
-                            self.material,
-                        ));
-                    }
-                    if depth > tmax {
+                // Sphere tracing needs a unit direction to step by the distance field. Stepping
+                // by the absolute distance also finds the surface from inside the object.
+                let scale = local_ray.dir().magnitude();
+                let dir = local_ray.dir() / scale;
+                let mut depth = tmin * scale;
+                for _ in 0..SDF_MAX_STEPS {
+                    if depth > tmax * scale {
                         return None;
                     }
+                    let pos = local_ray.pos() + dir * depth;
+                    let dist = sdf.sdf(pos).abs();
+                    if dist < SDF_HIT_DIST {
+                        let normal = sdf.sdf_d(pos);
+                        return Some(LocalHit {
+                            t: depth / scale,
+                            normal,
+                            geometric_normal: None,
+                            uv: TexCoords::Triplanar { pos, normal },
+                        });
+                    }
                     depth += dist;
-                    pos = local_ray.at(depth);
                 }
-                return None;
+                None
             }
             ObjectData::Sphere { radius } => {
                 let oc: Vector3<_> = local_ray.pos().to_vec();
@@ -138,20 +477,33 @@ impl Hittable for Object {
                 if t > tmax {
                     None
                 } else {
-                    Some(HitRecord::from_hit(
-                        ray,
-                        ray.at(t).to_vec() / *radius,
+                    let normal = local_ray.at(t).to_vec() / *radius;
+                    Some(LocalHit {
                         t,
-                        self.material,
-                    ))
+                        normal,
+                        geometric_normal: None,
+                        uv: sphere_uv(normal),
+                    })
                 }
             }
+            ObjectData::Mesh { mesh } => mesh.hit(local_ray, tmin, tmax).map(|h| LocalHit {
+                t: h.t,
+                normal: h.normal,
+                geometric_normal: Some(h.geometric_normal),
+                uv: TexCoords::Uv(h.uv),
+            }),
             ObjectData::Plane { normal } => {
                 let denominator = normal.dot(local_ray.dir());
-                if denominator > f64::EPSILON {
+                // Planes are two-sided: `HitRecord::from_hit` flips the normal towards the ray
+                if denominator.abs() > f64::EPSILON {
                     let t = local_ray.pos().to_vec().neg().dot(*normal) / denominator;
                     if (tmin..=tmax).contains(&t) {
-                        Some(HitRecord::from_hit(ray, *normal, t, self.material))
+                        Some(LocalHit {
+                            t,
+                            normal: *normal,
+                            geometric_normal: None,
+                            uv: plane_uv(local_ray.at(t).to_vec(), *normal),
+                        })
                     } else {
                         None
                     }
@@ -159,6 +511,607 @@ impl Hittable for Object {
                     None
                 }
             }
+            ObjectData::Group { .. } => unreachable!("groups are intersected by `Object::hit`"),
+        }
+    }
+}
+
+impl Hittable for Object {
+    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord<'_>> {
+        let local_ray = ray.transformed(&self.world_to_local);
+        // Normals transform with the inverse transpose of the object-to-world matrix
+        let to_world = |n| {
+            self.world_to_local
+                .transpose()
+                .transform_vector(n)
+                .normalize()
+        };
+        if let ObjectData::Group { children } = &*self.odata {
+            // `t` is the same in every space, so only the point and the normal need converting.
+            // The normal keeps facing the ray, as the transform preserves their dot product sign.
+            return children.hit(&local_ray, tmin, tmax).map(|h| {
+                let (material, material_id) = match &self.material {
+                    Some(m) => (&**m, self.material_id),
+                    None => (h.material, h.material_id),
+                };
+                HitRecord {
+                    point: ray.at(h.t),
+                    normal: to_world(h.normal),
+                    geometric_normal: to_world(h.geometric_normal),
+                    object_id: self.id,
+                    // Children report the index of their first leaf as their ID
+                    leaf: h.object_id + h.leaf,
+                    material,
+                    material_id,
+                    ..h
+                }
+            });
+        }
+        let material = self.material.as_deref().expect("Object without a material");
+        self.hit_local(&local_ray, tmin, tmax).map(|h| {
+            let hit = HitRecord::from_hit(ray, to_world(h.normal), h.t, h.uv, material);
+            HitRecord {
+                geometric_normal: h.geometric_normal.map_or(hit.geometric_normal, to_world),
+                object_id: self.id,
+                material_id: self.material_id,
+                ..hit
+            }
+        })
+    }
+}
+
+/// Spherical mapping of a point on the unit sphere: `u` goes around the Y axis, starting from
+/// -X, and `v` goes from the bottom (-Y) to the top (+Y).
+fn sphere_uv(p: V3) -> TexCoords {
+    let theta = (-p.y).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z).atan2(p.x) + PI;
+    TexCoords::Uv(Vector2::new(phi / (2.0 * PI), theta / PI))
+}
+
+/// Planar mapping of a point on a plane going through the origin, in units of world space.
+fn plane_uv(p: V3, normal: V3) -> TexCoords {
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    TexCoords::Uv(Vector2::new(p.dot(tangent), p.dot(bitangent)))
+}
+
+impl Bounded for Object {
+    fn bounding_box(&self) -> Option<Aabb> {
+        let local = match &*self.odata {
+            ObjectData::Sphere { radius } => {
+                Some(Aabb::from_half_size(V3::new(*radius, *radius, *radius)))
+            }
+            ObjectData::Plane { .. } => None,
+            ObjectData::SDF { sdf } => sdf.bounding_box(),
+            ObjectData::Mesh { mesh } => mesh.bounding_box(),
+            ObjectData::Group { children } => children.bounding_box(),
+        }?;
+        Some(local.transformed(&self.local_to_world))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sample_rng;
+
+    const MATERIAL: &str = "material: {type: Lambert, albedo: {color: [0.5, 0.5, 0.5]}}";
+
+    fn build(o: config::Object) -> Object {
+        Builder::default().build(o).unwrap()
+    }
+
+    /// Object from its YAML description as a flow mapping, without the material.
+    fn object(yaml: &str) -> Object {
+        let yaml = format!("{}, {}}}", yaml.strip_suffix('}').unwrap(), MATERIAL);
+        build(serde_yaml::from_str(&yaml).unwrap())
+    }
+
+    fn ray(from: [f64; 3], dir: [f64; 3]) -> Ray {
+        Ray::new(from.into(), dir.into())
+    }
+
+    /// Hit `obj` with `ray`, and check the hit distance and the world-space normal, which must
+    /// face the ray.
+    fn check_hit(obj: &Object, ray: Ray, t: f64, normal: [f64; 3]) {
+        // Sphere tracing only gets within `SDF_HIT_DIST` of the surface
+        let eps = match *obj.odata {
+            ObjectData::SDF { .. } => 1e-5,
+            _ => 1e-9,
+        };
+        let hit = obj
+            .hit(&ray, 0.001, f64::INFINITY)
+            .unwrap_or_else(|| panic!("{:?} missed {:?}", ray, obj));
+        let normal = V3::from(normal);
+        assert!((hit.t - t).abs() < eps, "t = {}, expected {}", hit.t, t);
+        assert!(
+            (hit.normal - normal).magnitude() < eps,
+            "normal = {:?}, expected {:?}",
+            hit.normal,
+            normal
+        );
+        assert!((hit.point - ray.at(t)).magnitude() < eps);
+    }
+
+    fn check_miss(obj: &Object, ray: Ray) {
+        assert!(obj.hit(&ray, 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn sphere() {
+        let obj = object("{type: Sphere, pos: [1, 2, 3], radius: 0.5}");
+        check_hit(
+            &obj,
+            ray([1.0, 2.0, -2.0], [0.0, 0.0, 1.0]),
+            4.5,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([1.3, 2.0, -2.0], [0.0, 0.0, 1.0]),
+            4.6,
+            [0.6, 0.0, -0.8],
+        );
+        check_hit(
+            &obj,
+            ray([1.0, 7.0, 3.0], [0.0, -1.0, 0.0]),
+            4.5,
+            [0.0, 1.0, 0.0],
+        );
+        check_miss(&obj, ray([1.6, 2.0, -2.0], [0.0, 0.0, 1.0]));
+        check_miss(&obj, ray([1.0, 2.0, 4.0], [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn sphere_from_inside() {
+        let obj = object("{type: Sphere, pos: [1, 2, 3], radius: 0.5}");
+        let ray = Ray::new(P3::new(1.0, 2.0, 3.0), V3::unit_z());
+        let hit = obj.hit(&ray, 0.001, f64::INFINITY).unwrap();
+        assert!(!hit.front_face);
+        assert!((hit.t - 0.5).abs() < 1e-9);
+        assert!((hit.normal + V3::unit_z()).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn scaled_sphere() {
+        let obj =
+            object("{type: Sphere, pos: [0, 1, 0], radius: 1, transform: {scale: [2, 1, 1]}}");
+        check_hit(
+            &obj,
+            ray([-5.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+            3.0,
+            [-1.0, 0.0, 0.0],
+        );
+        // The normal of the ellipse x²/4 + y² = 1 at (x, y) is along (x / 4, y)
+        let (x, y) = (2f64.sqrt(), 0.5f64.sqrt());
+        let n = V3::new(x / 4.0, y, 0.0).normalize();
+        check_hit(
+            &obj,
+            ray([x, 6.0, 0.0], [0.0, -1.0, 0.0]),
+            5.0 - y,
+            n.into(),
+        );
+    }
+
+    #[test]
+    fn rotated_sphere() {
+        let obj = object(
+            "{type: Sphere, pos: [3, 0, 0], radius: 1, transform: {rotate: [0, 0, 90], scale: [2, 1, 1]}}",
+        );
+        // The long axis now points along Y
+        check_hit(
+            &obj,
+            ray([3.0, 5.0, 0.0], [0.0, -1.0, 0.0]),
+            3.0,
+            [0.0, 1.0, 0.0],
+        );
+        check_hit(
+            &obj,
+            ray([0.0, 0.0, 0.0], [1.0, 0.0, 0.0]),
+            2.0,
+            [-1.0, 0.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn plane() {
+        let obj = object("{type: Plane, pos: [0, -1, 0], normal: [0, 1, 0]}");
+        check_hit(
+            &obj,
+            ray([2.0, 1.0, 3.0], [0.0, -1.0, 0.0]),
+            2.0,
+            [0.0, 1.0, 0.0],
+        );
+        check_hit(
+            &obj,
+            ray([0.0, 1.0, 0.0], [1.0, -1.0, 0.0]),
+            8f64.sqrt(),
+            [0.0, 1.0, 0.0],
+        );
+        // Planes are two-sided
+        check_hit(
+            &obj,
+            ray([2.0, -4.0, 3.0], [0.0, 1.0, 0.0]),
+            3.0,
+            [0.0, -1.0, 0.0],
+        );
+        check_miss(&obj, ray([0.0, 1.0, 0.0], [0.0, 1.0, 0.0]));
+        check_miss(&obj, ray([0.0, 1.0, 0.0], [1.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn rotated_plane() {
+        let obj = object(
+            "{type: Plane, pos: [0, 0, 5], normal: [0, 1, 0], transform: {rotate: {axis: [1, 0, 0], angle: 90}}}",
+        );
+        check_hit(
+            &obj,
+            ray([1.0, 1.0, 10.0], [0.0, 0.0, -1.0]),
+            5.0,
+            [0.0, 0.0, 1.0],
+        );
+        check_hit(
+            &obj,
+            ray([1.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+            5.0,
+            [0.0, 0.0, -1.0],
+        );
+    }
+
+    #[test]
+    fn misspelled_transform() {
+        let transform = |yaml| serde_yaml::from_str::<config::Transform>(yaml);
+        assert!(transform("{rotate: [0, 90, 0], translate: [1, 0, 0]}").is_ok());
+        assert!(transform("{rotation: [0, 90, 0]}").is_err());
+        assert!(transform("{translation: [1, 0, 0]}").is_err());
+    }
+
+    #[test]
+    fn small_scales() {
+        let obj = object("{type: Sphere, radius: 1e6, transform: {scale: 1e-6}}");
+        check_hit(
+            &obj,
+            ray([0.0, 0.0, -2.0], [0.0, 0.0, 1.0]),
+            1.0,
+            [0.0, 0.0, -1.0],
+        );
+        // The determinant of the transform underflows
+        let world = serde_yaml::from_str(&format!(
+            "[{{type: Sphere, radius: 1, transform: {{scale: 1e-110}}, {}}}]",
+            MATERIAL
+        ))
+        .unwrap();
+        assert!(build_world(world, BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn sdf_sphere() {
+        let obj = object("{type: SDF, pos: [1, 2, 3], sdf: {type: Sphere, radius: 0.5}}");
+        check_hit(
+            &obj,
+            ray([1.0, 2.0, -2.0], [0.0, 0.0, 1.0]),
+            4.5,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([1.3, 2.0, -2.0], [0.0, 0.0, 1.0]),
+            4.6,
+            [0.6, 0.0, -0.8],
+        );
+        check_miss(&obj, ray([1.6, 2.0, -2.0], [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn sdf_sphere_from_inside() {
+        let obj = object("{type: SDF, pos: [1, 2, 3], sdf: {type: Sphere, radius: 0.5}}");
+        check_hit(
+            &obj,
+            ray([1.0, 2.0, 3.0], [0.0, 0.0, 1.0]),
+            0.5,
+            [0.0, 0.0, -1.0],
+        );
+    }
+
+    #[test]
+    fn scaled_sdf_sphere() {
+        let obj = object(
+            "{type: SDF, pos: [1, 2, 3], sdf: {type: Sphere, radius: 0.5}, transform: {scale: 2}}",
+        );
+        check_hit(
+            &obj,
+            ray([1.0, 2.0, -2.0], [0.0, 0.0, 1.0]),
+            4.0,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([1.6, 2.0, -2.0], [0.0, 0.0, 1.0]),
+            4.2,
+            [0.6, 0.0, -0.8],
+        );
+    }
+
+    #[test]
+    fn sdf_plane() {
+        let obj = object("{type: SDF, pos: [0, -1, 0], sdf: {type: Plane, normal: [0, 2, 0]}}");
+        check_hit(
+            &obj,
+            ray([2.0, 1.0, 3.0], [0.0, -1.0, 0.0]),
+            2.0,
+            [0.0, 1.0, 0.0],
+        );
+        check_hit(
+            &obj,
+            ray([0.0, 1.0, 0.0], [1.0, -1.0, 0.0]),
+            8f64.sqrt(),
+            [0.0, 1.0, 0.0],
+        );
+        check_miss(&obj, ray([0.0, 1.0, 0.0], [0.0, 1.0, 0.0]));
+    }
+
+    #[test]
+    fn sdf_box() {
+        let obj = object("{type: SDF, pos: [0, 0, 4], sdf: {type: Box, size: [1, 0.5, 0.5]}}");
+        check_hit(
+            &obj,
+            ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            3.5,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([5.0, 0.2, 4.0], [-1.0, 0.0, 0.0]),
+            4.0,
+            [1.0, 0.0, 0.0],
+        );
+        check_hit(
+            &obj,
+            ray([0.5, -3.0, 4.1], [0.0, 1.0, 0.0]),
+            2.5,
+            [0.0, -1.0, 0.0],
+        );
+        check_miss(&obj, ray([0.0, 0.6, 0.0], [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn sdf_rounding() {
+        let obj = object(
+            "{type: SDF, pos: [0, 0, 3], sdf: {type: Rounding, amount: 0.25, sdf: {type: Box, size: [0.5, 0.5, 0.5]}}}",
+        );
+        check_hit(
+            &obj,
+            ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            2.25,
+            [0.0, 0.0, -1.0],
+        );
+        // Around the edges the surface is a quarter circle of radius `amount`
+        let d = 0.25 * 0.5f64.sqrt();
+        check_hit(
+            &obj,
+            ray([0.5 + d, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            2.5 - d,
+            [0.5f64.sqrt(), 0.0, -(0.5f64.sqrt())],
+        );
+    }
+
+    #[test]
+    fn sdf_union() {
+        let obj = object(
+            "{type: SDF, pos: [0, 0, 5], sdf: {type: Union, smooth: 0.01,
+              left: {type: Sphere, pos: [-1, 0, 0], radius: 0.5},
+              right: {type: Box, pos: [1, 0, 0], size: [0.5, 0.5, 0.5]}}}",
+        );
+        check_hit(
+            &obj,
+            ray([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            4.5,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([1.2, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            4.5,
+            [0.0, 0.0, -1.0],
+        );
+        check_miss(&obj, ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn sdf_intersection() {
+        let obj = object(
+            "{type: SDF, pos: [0, 0, 5], sdf: {type: Intersection, smooth: 0.01,
+              left: {type: Sphere, pos: [0, 0, 0], radius: 1},
+              right: {type: Box, pos: [0, 0, 0], size: [2, 2, 0.5]}}}",
+        );
+        check_hit(
+            &obj,
+            ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            4.5,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([-3.0, 0.0, 5.0], [1.0, 0.0, 0.0]),
+            2.0,
+            [-1.0, 0.0, 0.0],
+        );
+        check_miss(&obj, ray([0.95, 0.4, 0.0], [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn nested_groups() {
+        let obj = build(
+            serde_yaml::from_str(&format!(
+                "{{type: Group, pos: [0, 0, 5], transform: {{scale: 2}}, children: [
+                  {{type: Group, transform: {{rotate: [0, 90, 0]}}, children: [
+                    {{type: Sphere, pos: [1, 0, 0], radius: 0.5, {}}}]}}]}}",
+                MATERIAL
+            ))
+            .unwrap(),
+        );
+        // The sphere is rotated to (0, 0, -1), then scaled and moved to (0, 0, 3)
+        check_hit(
+            &obj,
+            ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            2.0,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &obj,
+            ray([-5.0, 0.0, 3.0], [1.0, 0.0, 0.0]),
+            4.0,
+            [-1.0, 0.0, 0.0],
+        );
+        check_miss(&obj, ray([1.1, 0.0, 0.0], [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn instances() {
+        let definitions = serde_yaml::from_str(&format!(
+            "{{ball: {{type: Sphere, radius: 1, {}}},
+              pair: {{type: Group, children: [{{type: Instance, of: ball}},
+                                              {{type: Instance, of: ball, pos: [3, 0, 0]}}]}}}}",
+            MATERIAL
+        ))
+        .unwrap();
+        let world = serde_yaml::from_str(
+            "[{type: Instance, of: ball, pos: [0, 0, 5]},
+              {type: Instance, of: ball, pos: [0, 0, -5], transform: {scale: 2},
+               material_override: {type: Metal, albedo: {color: [1, 1, 1]}}},
+              {type: Instance, of: pair, pos: [0, 5, 0]}]",
+        )
+        .unwrap();
+        let world = build_world(world, definitions).unwrap();
+        assert!(Arc::ptr_eq(&world[0].odata, &world[1].odata));
+        assert_eq!((world[0].material_id, world[1].material_id), (0, 1));
+        check_hit(
+            &world[0],
+            ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            4.0,
+            [0.0, 0.0, -1.0],
+        );
+        check_hit(
+            &world[1],
+            ray([0.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            3.0,
+            [0.0, 0.0, 1.0],
+        );
+        // Hits inside an instanced group report the instance
+        let hit = world[2]
+            .hit(&ray([3.0, 0.0, 0.0], [0.0, 1.0, 0.0]), 0.001, f64::INFINITY)
+            .unwrap();
+        assert_eq!((hit.t, hit.object_id, hit.material_id), (4.0, 2, 0));
+
+        let cycle = serde_yaml::from_str(
+            "{a: {type: Group, children: [{type: Instance, of: b}]},
+              b: {type: Instance, of: a}}",
+        )
+        .unwrap();
+        let world = serde_yaml::from_str("[{type: Instance, of: a}]").unwrap();
+        assert!(build_world(world, cycle).is_err());
+        let world = serde_yaml::from_str("[{type: Instance, of: c}]").unwrap();
+        assert!(build_world(world, BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn undecodable_texture() {
+        let path = std::env::temp_dir().join(format!("raytracer-bad-{}.png", std::process::id()));
+        std::fs::write(&path, "not a png").unwrap();
+        let world = serde_yaml::from_str(&format!(
+            "[{{type: Sphere, radius: 1, material: {{type: Lambert, albedo: {{filename: {:?}}}}}}}]",
+            path
+        ))
+        .unwrap();
+        let built = build_world(world, BTreeMap::new());
+        std::fs::remove_file(&path).unwrap();
+        assert!(built.unwrap_err().starts_with("cannot load texture"));
+    }
+
+    /// Directions sampled on an emissive mesh get the same density from `Light::pdf` when a
+    /// ray hits the mesh along them, even where vertex normals lean away from the triangles.
+    #[test]
+    fn mesh_light_pdf() {
+        let path = std::env::temp_dir().join(format!("raytracer-light-{}.obj", std::process::id()));
+        std::fs::write(
+            &path,
+            "v -1 0 -1\nv 1 0 -1\nv 1 0 1\nv -1 0 1\n\
+             vn 0.5 1 0\nvn -0.5 1 0.3\nvn 0 1 -0.5\nvn 0.2 1 0.2\n\
+             f 1//1 2//2 3//3 4//4",
+        )
+        .unwrap();
+        let world = serde_yaml::from_str(&format!(
+            "[{{type: Mesh, filename: {:?}, pos: [0, 2, 0], transform: {{rotate: [20, 0, 10]}}, \
+             material: {{type: Emissive, color: {{color: [1, 1, 1]}}}}}}]",
+            path
+        ))
+        .unwrap();
+        let built = build_world(world, BTreeMap::new());
+        std::fs::remove_file(&path).unwrap();
+        let mesh = &built.unwrap()[0];
+        let light = &mesh.lights()[0];
+        let mut rng = sample_rng(3, 0, 0, 0);
+        let from = P3::new(0.3, 0.0, -0.2);
+        for _ in 0..100 {
+            let sample = light.sample(&mut rng, from).unwrap();
+            let hit = mesh
+                .hit(&Ray::new(from, sample.dir), 0.001, f64::INFINITY)
+                .unwrap();
+            assert!((hit.t - sample.dist).abs() < 1e-9);
+            let pdf = light.pdf(from, sample.dir, Some(&hit));
+            assert!(
+                (pdf / sample.pdf - 1.0).abs() < 1e-9,
+                "{} {}",
+                pdf,
+                sample.pdf
+            );
+        }
+    }
+
+    /// Emissive leaves of groups and of their instances are lights, which hits report.
+    #[test]
+    fn group_lights() {
+        let light = "{type: Emissive, color: {color: [1, 1, 1]}}";
+        let definitions = serde_yaml::from_str(&format!(
+            "lamps: {{type: Group, children: [
+               {{type: Sphere, radius: 0.5, {}}},
+               {{type: Group, transform: {{translate: [2, 0, 0]}}, children: [
+                 {{type: Sphere, radius: 0.5, material: {light}}},
+                 {{type: Sphere, pos: [0, 2, 0], radius: 0.5, material: {light}}}]}},
+               {{type: Sphere, pos: [-2, 0, 0], radius: 0.5, material: {light}}}]}}",
+            MATERIAL,
+            light = light
+        ))
+        .unwrap();
+        let world = serde_yaml::from_str(
+            "[{type: Sphere, pos: [0, -5, 0], radius: 1, material: {type: Metal, albedo: {color: [1, 1, 1]}}},
+              {type: Instance, of: lamps, pos: [0, 0, 10]},
+              {type: Instance, of: lamps, pos: [0, 0, -10], transform: {scale: 2}}]",
+        )
+        .unwrap();
+        let world = build_world(world, definitions).unwrap();
+        let lights: Vec<_> = world.iter().flat_map(Object::lights).collect();
+        let surfaces: Vec<_> = lights.iter().filter_map(Light::surface).collect();
+        assert_eq!(surfaces.len(), 6);
+        for &object_id in &[1, 2] {
+            for &leaf in &[1, 2, 3] {
+                assert!(surfaces.contains(&(object_id, leaf)));
+            }
+        }
+        let mut rng = sample_rng(4, 0, 0, 0);
+        let from = P3::new(0.0, 0.0, 0.0);
+        for light in &lights {
+            let sample = light.sample(&mut rng, from).unwrap();
+            let hit = world
+                .hit(&Ray::new(from, sample.dir), 0.001, f64::INFINITY)
+                .unwrap();
+            assert_eq!(Some((hit.object_id, hit.leaf)), light.surface());
+            let pdf = light.pdf(from, sample.dir, Some(&hit));
+            assert!(
+                (pdf / sample.pdf - 1.0).abs() < 1e-9,
+                "{} {}",
+                pdf,
+                sample.pdf
+            );
         }
     }
 }
@@ -0,0 +1,279 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use cgmath::Zero;
+use image::{ImageBuffer, Rgb};
+
+use crate::{scene::Block, tiles::Tile, Color};
+
+/// Output file formats, selected from the output file extension.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Binary (P6) PPM, 8-bit sRGB
+    Ppm,
+    /// PNG, 8 or 16-bit sRGB
+    Png,
+    /// OpenEXR, 32-bit float linear
+    Exr,
+    /// Portable float map, 32-bit float linear
+    Pfm,
+}
+
+impl Format {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "ppm" => Some(Self::Ppm),
+            "png" => Some(Self::Png),
+            "exr" => Some(Self::Exr),
+            "pfm" => Some(Self::Pfm),
+            _ => None,
+        }
+    }
+}
+
+/// Rendered image, in linear color space, stored top row first.
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+}
+
+/// Running sums of the samples rendered for each pixel, from which the image is resolved.
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    width: u32,
+    height: u32,
+    sums: Vec<Color>,
+    counts: Vec<u32>,
+    /// Values of each AOV, for each pixel
+    aovs: Vec<Vec<Color>>,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        let len = (width * height) as usize;
+        Self {
+            width,
+            height,
+            sums: vec![Color::zero(); len],
+            counts: vec![0; len],
+            aovs: vec![],
+        }
+    }
+
+    pub fn add(&mut self, block: &Block) {
+        for (((i, j), color), samples) in block.tile.pixels().zip(&block.pixels).zip(&block.samples)
+        {
+            let idx = (j * self.width + i) as usize;
+            self.sums[idx] += *color;
+            self.counts[idx] += samples;
+        }
+        for ((i, j), values) in block.tile.pixels().zip(&block.aovs) {
+            let idx = (j * self.width + i) as usize;
+            if self.aovs.len() < values.len() {
+                let len = self.sums.len();
+                self.aovs.resize(values.len(), vec![Color::zero(); len]);
+            }
+            for (aov, value) in self.aovs.iter_mut().zip(values) {
+                aov[idx] = *value;
+            }
+        }
+    }
+
+    /// Image of the `index`-th AOV of the scene.
+    pub fn aov(&self, index: usize) -> Framebuffer {
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self
+                .aovs
+                .get(index)
+                .cloned()
+                .unwrap_or_else(|| vec![Color::zero(); self.sums.len()]),
+        }
+    }
+
+    /// Heat map of the number of samples taken for each pixel, going from black for none to
+    /// red, yellow and white for `max_samples`.
+    pub fn sample_map(&self, max_samples: u32) -> Framebuffer {
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self
+                .counts
+                .iter()
+                .map(|&count| {
+                    let t = 3.0 * count as f64 / max_samples.max(1) as f64;
+                    Color::new(t, t - 1.0, t - 2.0).map(|x| x.clamp(0.0, 1.0))
+                })
+                .collect(),
+        }
+    }
+
+    /// Average of the samples so far; pixels without any samples are black.
+    pub fn framebuffer(&self) -> Framebuffer {
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self
+                .sums
+                .iter()
+                .zip(&self.counts)
+                .map(|(sum, &count)| {
+                    if count > 0 {
+                        sum / count as f64
+                    } else {
+                        Color::zero()
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Framebuffer {
+    /// Part of the image covered by `area`.
+    pub fn crop(&self, area: &Tile) -> Framebuffer {
+        Framebuffer {
+            width: area.width,
+            height: area.height,
+            pixels: area
+                .pixels()
+                .map(|(i, j)| self.pixels[(j * self.width + i) as usize])
+                .collect(),
+        }
+    }
+
+    pub fn to_srgb8(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|c| [c.x, c.y, c.z])
+            .map(|x| (linear_to_srgb(x) * 255.0).round() as u8)
+            .collect()
+    }
+
+    pub fn to_srgb16(&self) -> Vec<u16> {
+        self.pixels
+            .iter()
+            .flat_map(|c| [c.x, c.y, c.z])
+            .map(|x| (linear_to_srgb(x) * 65535.0).round() as u16)
+            .collect()
+    }
+
+    pub fn to_f32(&self) -> Vec<f32> {
+        self.pixels
+            .iter()
+            .flat_map(|c| [c.x, c.y, c.z])
+            .map(|x| x as f32)
+            .collect()
+    }
+
+    /// Write the image to `path`, in the format given by its extension. `bit_depth` is only used
+    /// by PNG output, and must be 8 or 16.
+    pub fn write<P: AsRef<Path>>(&self, path: P, bit_depth: u8) -> io::Result<()> {
+        let path = path.as_ref();
+        match Format::from_path(path) {
+            Some(Format::Ppm) => self.write_ppm(BufWriter::new(File::create(path)?)),
+            Some(Format::Pfm) => self.write_pfm(BufWriter::new(File::create(path)?)),
+            Some(Format::Png) if bit_depth == 16 => {
+                ImageBuffer::<Rgb<u16>, _>::from_raw(self.width, self.height, self.to_srgb16())
+                    .unwrap()
+                    .save(path)
+                    .map_err(to_io_error)
+            }
+            Some(Format::Png) => {
+                ImageBuffer::<Rgb<u8>, _>::from_raw(self.width, self.height, self.to_srgb8())
+                    .unwrap()
+                    .save(path)
+                    .map_err(to_io_error)
+            }
+            Some(Format::Exr) => {
+                ImageBuffer::<Rgb<f32>, _>::from_raw(self.width, self.height, self.to_f32())
+                    .unwrap()
+                    .save(path)
+                    .map_err(to_io_error)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown output format for {}", path.display()),
+            )),
+        }
+    }
+
+    /// Write the image to an OpenEXR file as its RGB channels, along with extra layers whose
+    /// channels are named `<layer>.<channel>`. Layers with a single channel only store the
+    /// first component of their pixels.
+    pub fn write_exr_layers<P: AsRef<Path>>(
+        &self,
+        path: P,
+        layers: &[(&str, &[&str], Framebuffer)],
+    ) -> io::Result<()> {
+        use exr::prelude::{
+            AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes,
+            SmallVec, WritableImage,
+        };
+
+        let channel = |fb: &Framebuffer, name: String, component: usize| {
+            // EXR scan lines go from top to bottom, like ours
+            let samples = fb.pixels.iter().map(|c| c[component] as f32).collect();
+            AnyChannel::new(name.as_str(), FlatSamples::F32(samples))
+        };
+        let mut channels: SmallVec<[_; 4]> = ["R", "G", "B"]
+            .iter()
+            .enumerate()
+            .map(|(k, name)| channel(self, name.to_string(), k))
+            .collect();
+        for (layer, names, fb) in layers {
+            for (k, name) in names.iter().enumerate() {
+                channels.push(channel(fb, format!("{}.{}", layer, name), k));
+            }
+        }
+        let layer = Layer::new(
+            (self.width as usize, self.height as usize),
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels),
+        );
+        Image::from_layer(layer)
+            .write()
+            .to_file(path)
+            .map_err(io::Error::other)
+    }
+
+    pub fn write_ppm<W: Write>(&self, mut w: W) -> io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.width, self.height)?;
+        w.write_all(&self.to_srgb8())?;
+        w.flush()
+    }
+
+    pub fn write_pfm<W: Write>(&self, mut w: W) -> io::Result<()> {
+        // A negative scale marks little-endian data; rows are stored bottom to top
+        write!(w, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width as usize).rev() {
+            for c in row {
+                for x in &[c.x, c.y, c.z] {
+                    w.write_all(&(*x as f32).to_le_bytes())?;
+                }
+            }
+        }
+        w.flush()
+    }
+}
+
+fn to_io_error(err: image::ImageError) -> io::Error {
+    io::Error::other(err)
+}
+
+pub fn linear_to_srgb(x: f64) -> f64 {
+    let x = x.clamp(0.0, 1.0);
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
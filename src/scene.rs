@@ -1,31 +1,112 @@
-use cgmath::{ElementWise, Zero};
-use rand::{prelude::*, thread_rng, Rng};
+use cgmath::{Array, ElementWise, EuclideanSpace, InnerSpace, Zero};
+use rand::Rng;
 use rayon::prelude::*;
-use std::f64::*;
 
 use crate::{
-    camera::Camera, config, material::Bounce, ray::Ray, sky::Sky, traits::Hittable, Color,
+    bvh::Bvh,
+    camera::Camera,
+    config::{self, Aov},
+    light::{power_heuristic, Light},
+    material::Bounce,
+    medium::{Atmosphere, Interaction, Medium},
+    objects::{self, Object},
+    ray::Ray,
+    sky::Sky,
+    spectrum,
+    tiles::{region, tiles, Tile},
+    traits::{HitRecord, Hittable},
+    utils::{sample_rng, SampleRng},
+    Color, P3, V3,
 };
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Rendered tile, holding for each of its pixels the sum of the new samples and their number.
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub tile: Tile,
+    /// Index of the progressive pass this block belongs to
+    pub pass: usize,
+    pub samples: Vec<u32>,
+    pub pixels: Vec<Color>,
+    /// Values of the scene's AOVs for each pixel, only sent with the first pass
+    pub aovs: Vec<Vec<Color>>,
+}
+
+/// Running mean and variance of the luminance of a pixel's samples (Welford's algorithm).
+#[derive(Copy, Clone, Debug, Default)]
+struct PixelStats {
+    count: u32,
+    mean: f64,
+    m2: f64,
+    /// Whether adaptive sampling has determined that the pixel needs no more samples
+    converged: bool,
+}
+
+impl PixelStats {
+    fn add(&mut self, color: Color, adaptive: Option<&config::Adaptive>) {
+        let lum = 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+        self.count += 1;
+        let delta = lum - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (lum - self.mean);
+        if let Some(a) = adaptive {
+            self.converged = self.count >= a.min_samples.max(2) && self.error() < a.threshold;
+        }
+    }
+
+    /// Standard error of the mean, relative to the mean itself so that dark and bright pixels
+    /// are held to the same standard. Very dark pixels are compared to a floor instead.
+    fn error(&self) -> f64 {
+        let variance = self.m2 / (self.count - 1) as f64;
+        (variance / self.count as f64).sqrt() / self.mean.max(0.01)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Scene<W> {
     pub samples: u32,
     pub bounces: u32,
+    pub seed: u64,
+    pub tiles: config::Tiles,
+    pub progressive: bool,
+    pub region: Option<config::Region>,
+    pub crop: bool,
+    pub spectral: bool,
+    pub atmosphere: Option<Atmosphere>,
+    pub adaptive: Option<config::Adaptive>,
+    pub aovs: Vec<Aov>,
+    pub denoise: Option<config::Denoise>,
     pub camera: config::Camera,
     pub world: W,
     pub sky: Sky,
+    /// Light sources explicitly sampled at each non-specular hit
+    pub lights: Vec<Light>,
 }
 
-impl<'de, H, W: Deserialize<'de> + Into<H>> From<config::Scene<W>> for Scene<H> {
-    fn from(s: config::Scene<W>) -> Self {
-        Self {
+impl<'de, H, W: Deserialize<'de> + Into<H>> TryFrom<config::Scene<W>> for Scene<H> {
+    type Error = String;
+
+    fn try_from(s: config::Scene<W>) -> Result<Self, String> {
+        let sky = Sky::try_from(s.sky)?;
+        Ok(Self {
             samples: s.samples,
             bounces: s.bounces,
+            seed: s.seed,
+            tiles: s.tiles,
+            progressive: s.progressive,
+            region: s.region,
+            crop: s.crop,
+            spectral: s.spectral,
+            atmosphere: s.atmosphere.map(Atmosphere::from),
+            adaptive: s.adaptive,
+            aovs: s.aovs,
+            denoise: s.denoise,
             camera: s.camera,
             world: s.world.into(),
-            sky: Sky,
-        }
+            lights: sky.sun().map(Light::sun).into_iter().collect(),
+            sky,
+        })
     }
 }
 
@@ -34,8 +115,20 @@ impl<H: Into<W>, W: Serialize> From<Scene<H>> for config::Scene<W> {
         Self {
             bounces: scn.bounces,
             samples: scn.samples,
+            seed: scn.seed,
+            tiles: scn.tiles,
+            progressive: scn.progressive,
+            region: scn.region,
+            crop: scn.crop,
+            spectral: scn.spectral,
+            atmosphere: scn.atmosphere.map(config::Atmosphere::from),
+            adaptive: scn.adaptive,
+            aovs: scn.aovs,
+            denoise: scn.denoise,
+            objects: Default::default(),
             world: scn.world.into(),
-            camera: scn.camera.into(),
+            camera: scn.camera,
+            sky: scn.sky.into(),
         }
     }
 }
@@ -45,77 +138,567 @@ impl<W> Scene<W> {
         let Self {
             bounces,
             samples,
+            seed,
+            tiles,
+            progressive,
+            region,
+            crop,
+            spectral,
+            atmosphere,
+            adaptive,
+            aovs,
+            denoise,
             world,
             camera,
             sky,
+            lights,
         } = self;
         Scene {
             bounces,
             samples,
+            seed,
+            tiles,
+            progressive,
+            region,
+            crop,
+            spectral,
+            atmosphere,
+            adaptive,
+            aovs,
+            denoise,
             world: map(world),
             camera,
             sky,
+            lights,
         }
     }
 }
 
+impl Scene<Bvh<Object>> {
+    /// Build the objects of a scene and the lights they hold, ready for rendering.
+    pub fn build(mut config: config::Scene<Vec<config::Object>>) -> Result<Self, String> {
+        let definitions = std::mem::take(&mut config.objects);
+        let world = objects::build_world(std::mem::take(&mut config.world), definitions)?;
+        let mut scn = Scene::<Vec<config::Object>>::try_from(config)?.map_world(|_| world);
+        scn.lights.extend(scn.world.iter().flat_map(Object::lights));
+        Ok(scn.map_world(Bvh::new))
+    }
+}
+
 impl<W: 'static + Hittable + Send> Scene<W> {
-    pub fn run(self, width: u32, height: u32) -> impl Iterator<Item = Vec<Color>> {
-        let distr = rand::distributions::Uniform::new(0.0, 1.0);
-        let (tx, rx) = crossbeam::channel::unbounded::<Vec<Color>>();
+    /// Render the image, sending back each tile as soon as it is done. In progressive mode,
+    /// the whole image is rendered once per sample, otherwise each tile gets all its samples
+    /// at once.
+    pub fn run(self, width: u32, height: u32) -> impl Iterator<Item = Block> {
+        let (tx, rx) = crossbeam::channel::unbounded::<Block>();
         let cam = Camera::from_config(self.camera, width as f64 / height as f64);
+        let tiles = tiles(self.render_area(width, height), self.tiles);
+        let passes: Vec<_> = if self.progressive {
+            (0..self.samples).map(|s| s..s + 1).collect()
+        } else {
+            std::iter::once(0..self.samples).collect()
+        };
+
+        // Statistics of each pixel, kept across passes, grouped by tile
+        let mut stats: Vec<Vec<PixelStats>> = tiles
+            .iter()
+            .map(|t| vec![PixelStats::default(); (t.width * t.height) as usize])
+            .collect();
 
-        std::thread::spawn(move || {
-            for j in (0..height).rev() {
-                let row: Vec<Color> = (0..width)
-                    .into_par_iter()
-                    .map(|i| {
-                        let mut rng = thread_rng();
-                        (0..self.samples)
-                            .map(|_| {
-                                let u = (i as f64 + rng.sample(distr)) / (width - 1) as f64;
-                                let v = (j as f64 + rng.sample(distr)) / (height - 1) as f64;
-                                let ray = cam.get_ray(&mut rng, u, v);
-                                self.ray_color(&mut rng, ray, self.bounces)
+        // Rendering happens on the current thread pool, which callers can pick with
+        // `ThreadPool::install`
+        rayon::spawn(move || {
+            for (pass, samples) in passes.into_iter().enumerate() {
+                // Bridging keeps the tiles starting in order, unlike splitting the list
+                tiles
+                    .iter()
+                    .zip(&mut stats)
+                    .par_bridge()
+                    .for_each(|(tile, stats)| {
+                        let (pixels, samples) = tile
+                            .pixels()
+                            .zip(stats)
+                            .map(|((i, j), stats)| {
+                                let j = height - 1 - j;
+                                let mut sum = Color::zero();
+                                let mut count = 0;
+                                for s in samples.clone() {
+                                    if stats.converged {
+                                        break;
+                                    }
+                                    let color = self.sample(&cam, width, height, i, j, s);
+                                    sum += color;
+                                    count += 1;
+                                    stats.add(color, self.adaptive.as_ref());
+                                }
+                                (sum, count)
                             })
-                            .sum::<Color>()
-                            / self.samples as f64
-                    })
-                    .collect();
-                tx.send(row).unwrap();
+                            .unzip();
+                        let aovs = if pass == 0 && !self.aovs.is_empty() {
+                            tile.pixels()
+                                .map(|(i, j)| self.aovs(&cam, width, height, i, height - 1 - j))
+                                .collect()
+                        } else {
+                            vec![]
+                        };
+                        tx.send(Block {
+                            tile: *tile,
+                            pass,
+                            samples,
+                            pixels,
+                            aovs,
+                        })
+                        .unwrap();
+                    });
             }
             drop(tx);
         });
         rx.into_iter()
     }
 
-    fn ray_color(&self, rng: &mut ThreadRng, ray: Ray, depth: u32) -> Color {
+    /// Part of a `width` x `height` image covered by the render region.
+    pub fn render_area(&self, width: u32, height: u32) -> Tile {
+        region(width, height, self.region)
+    }
+
+    /// Radiance carried by sample `s` of pixel (`i`, `j`), `j` being counted from the bottom.
+    fn sample(&self, cam: &Camera, width: u32, height: u32, i: u32, j: u32, s: u32) -> Color {
+        let distr = rand::distributions::Uniform::new(0.0, 1.0);
+        let mut rng = sample_rng(self.seed, i, j, s);
+        let u = (i as f64 + rng.sample(distr)) / (width - 1) as f64;
+        let v = (j as f64 + rng.sample(distr)) / (height - 1) as f64;
+        let ray = cam.get_ray(&mut rng, u, v);
+        let medium = self.atmosphere();
+        if self.spectral {
+            let wavelength = spectrum::sample_wavelength(rng.sample(distr));
+            let color = self.ray_color(
+                &mut rng,
+                ray,
+                self.bounces,
+                None,
+                wavelength,
+                medium,
+                Interior::default(),
+            );
+            color.mul_element_wise(spectrum::rgb_weight(wavelength))
+        } else {
+            let wavelength = spectrum::REFERENCE_WAVELENGTH;
+            self.ray_color(
+                &mut rng,
+                ray,
+                self.bounces,
+                None,
+                wavelength,
+                medium,
+                Interior::default(),
+            )
+        }
+    }
+
+    /// Values of the AOVs for the surface seen through the center of pixel (`i`, `j`), `j` being
+    /// counted from the bottom.
+    fn aovs(&self, cam: &Camera, width: u32, height: u32, i: u32, j: u32) -> Vec<Color> {
+        let mut rng = sample_rng(self.seed, i, j, 0);
+        let u = (i as f64 + 0.5) / (width - 1) as f64;
+        let v = (j as f64 + 0.5) / (height - 1) as f64;
+        let ray = cam.get_ray(&mut rng, u, v);
+        let hit = self.world.hit(&ray, 0.001, f64::INFINITY);
+        self.aovs
+            .iter()
+            .map(|aov| match (aov, &hit) {
+                (Aov::Depth, Some(h)) => Color::from_value(h.t),
+                (Aov::Depth, None) => Color::from_value(f64::INFINITY),
+                (Aov::Normal, Some(h)) => h.normal,
+                (Aov::Albedo, Some(h)) => h.material.albedo(h),
+                (Aov::Position, Some(h)) => h.point.to_vec(),
+                (Aov::Object, Some(h)) => Color::from_value(h.object_id as f64 + 1.0),
+                (Aov::Material, Some(h)) => Color::from_value(h.material_id as f64 + 1.0),
+                (_, None) => Color::zero(),
+            })
+            .collect()
+    }
+
+    /// Radiance arriving along `ray`, which starts in `medium` and inside the dielectrics of
+    /// `interior`. `bsdf_pdf` is the density with which the previous hit sampled this ray, or
+    /// `None` if it comes from the camera or a specular bounce; it is needed to weight emission
+    /// found by chance against explicit light sampling. Materials which depend on the wavelength
+    /// are evaluated at `wavelength`, in nanometers.
+    #[allow(clippy::too_many_arguments)]
+    fn ray_color<'a>(
+        &'a self,
+        rng: &mut SampleRng,
+        ray: Ray,
+        depth: u32,
+        bsdf_pdf: Option<f64>,
+        wavelength: f64,
+        medium: Option<&'a Medium>,
+        interior: Interior,
+    ) -> Color {
         if depth == 0 {
-            Color::zero()
+            return Color::zero();
+        }
+        // Volume boundaries don't scatter light, so the ray goes on through them. It keeps its
+        // origin, from which light densities are computed.
+        let (origin, dir) = (ray.pos(), ray.dir());
+        let (mut segment, mut medium) = (ray, medium);
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let hit = loop {
+            let hit = self.world.hit(&segment, 0.001, f64::INFINITY);
+            if let Some(m) = medium {
+                let end = hit.as_ref().map_or(self.escape_distance(), |h| h.t);
+                match m.sample_distance(rng, end) {
+                    Interaction::Scatter { t, weight } => {
+                        let point = segment.at(t);
+                        let inscattered =
+                            self.medium_color(rng, point, dir, m, interior, depth, wavelength);
+                        return throughput
+                            .mul_element_wise(weight)
+                            .mul_element_wise(interior.absorption(t))
+                            .mul_element_wise(inscattered);
+                    }
+                    Interaction::Pass { weight } => {
+                        throughput = throughput.mul_element_wise(weight);
+                    }
+                }
+            }
+            let end = hit.as_ref().map_or(f64::INFINITY, |h| h.t);
+            throughput = throughput.mul_element_wise(interior.absorption(end));
+            match hit {
+                Some(h) if h.material.is_boundary() => {
+                    medium = self.medium_behind(&h);
+                    segment = Ray::new(h.point, dir);
+                }
+                hit => break hit,
+            }
+        };
+        let radiance = if let Some(h) = hit {
+            let emitted = h.material.emitted(&h);
+            let emitted = match bsdf_pdf {
+                Some(pdf) if !emitted.is_zero() => {
+                    let light_pdf = self.light_pdf(origin, dir, Some(&h));
+                    emitted * power_heuristic(pdf, light_pdf)
+                }
+                _ => emitted,
+            };
+            let wo = -dir;
+            let direct = if h.material.pdf(&h, wo, h.normal).is_some() {
+                self.sample_light(rng, h.point, Some(h.normal), medium, interior, |wi| {
+                    let f = h.material.eval(&h, wo, wi).unwrap_or_else(Color::zero);
+                    (f, h.material.pdf(&h, wo, wi).unwrap_or(0.0))
+                })
+            } else {
+                Color::zero()
+            };
+            let follow = |rng: &mut SampleRng, scattered: Ray, pdf: Option<f64>| {
+                // Rays going through the surface change medium, and enter or leave dielectrics
+                let (medium, interior) = if scattered.dir().dot(h.normal) < 0.0 {
+                    let medium = self.medium_behind(&h);
+                    match h.material.interior(&h) {
+                        Some(inside) if h.front_face => (medium, interior.enter(inside)),
+                        Some(_) => (medium, interior.leave()),
+                        None => (medium, interior),
+                    }
+                } else {
+                    (medium, interior)
+                };
+                self.ray_color(rng, scattered, depth - 1, pdf, wavelength, medium, interior)
+            };
+            let scattered = match h.material.scatter(rng, &segment, &h, wavelength) {
+                Bounce::Stop(col) => col,
+                Bounce::Bounce(color, _) | Bounce::Specular(color, _) if depth == 1 => color,
+                Bounce::Bounce(color, scattered) => {
+                    let pdf = h.material.pdf(&h, wo, scattered.dir());
+                    color.mul_element_wise(follow(rng, scattered, pdf))
+                }
+                Bounce::Specular(color, scattered) => {
+                    color.mul_element_wise(follow(rng, scattered, None))
+                }
+            };
+            emitted + direct + scattered
         } else {
-            if let Some(h) = self.world.hit(&ray, 0.001, INFINITY) {
-                match h.material.scatter(&ray, &h) {
-                    Bounce::Bounce(color, ray) => {
-                        if depth == 1 {
-                            color
+            #[cfg(not(feature = "debug_normals"))]
+            {
+                let color = self.sky.get_color(dir);
+                match bsdf_pdf {
+                    Some(pdf) => {
+                        let light_pdf = self.light_pdf(origin, dir, None);
+                        if light_pdf > 0.0 {
+                            color * power_heuristic(pdf, light_pdf)
                         } else {
-                            let inner = self.ray_color(rng, ray, depth - 1);
-                            color.mul_element_wise(inner)
+                            color
                         }
                     }
-                    Bounce::Sky(ray) => self.sky.get_color(ray.dir()),
-                    Bounce::Stop(col) => col,
+                    None => color,
                 }
-            } else {
-                #[cfg(not(feature = "debug_normals"))]
-                {
-                    self.sky.get_color(ray.dir())
-                }
-                #[cfg(feature = "debug_normals")]
-                {
-                    Color::zero()
+            }
+            #[cfg(feature = "debug_normals")]
+            {
+                Color::zero()
+            }
+        };
+        throughput.mul_element_wise(radiance)
+    }
+
+    /// Radiance scattered at `point` inside `medium` towards the ray propagating along `dir`.
+    #[allow(clippy::too_many_arguments)]
+    fn medium_color<'a>(
+        &'a self,
+        rng: &mut SampleRng,
+        point: P3,
+        dir: V3,
+        medium: &'a Medium,
+        interior: Interior,
+        depth: u32,
+        wavelength: f64,
+    ) -> Color {
+        let direct = self.sample_light(rng, point, None, Some(medium), interior, |wi| {
+            let phase = medium.phase(dir, wi);
+            (Color::from_value(phase), phase)
+        });
+        let wi = medium.sample_phase(rng, dir);
+        let pdf = medium.phase(dir, wi);
+        let ray = Ray::new(point, wi);
+        let medium = Some(medium);
+        direct + self.ray_color(rng, ray, depth - 1, Some(pdf), wavelength, medium, interior)
+    }
+
+    /// Medium filling the scene outside of objects, if any.
+    fn atmosphere(&self) -> Option<&Medium> {
+        self.atmosphere.as_ref().map(|a| &a.medium)
+    }
+
+    /// Distance travelled through the atmosphere by rays which don't hit anything.
+    fn escape_distance(&self) -> f64 {
+        self.atmosphere.map_or(f64::INFINITY, |a| a.distance)
+    }
+
+    /// Medium on the other side of the surface at `hit`. Media don't nest: the inside of
+    /// objects other than volumes is empty, and their outside is the atmosphere.
+    fn medium_behind<'a>(&'a self, hit: &HitRecord<'a>) -> Option<&'a Medium> {
+        if hit.front_face {
+            hit.material.medium()
+        } else {
+            self.atmosphere()
+        }
+    }
+
+    /// Follows a shadow ray from inside `medium` and the dielectrics of `interior` for `dist`,
+    /// through volume boundaries. Returns the transmittance of the media crossed, and the first
+    /// surface found.
+    fn shadow<'a>(
+        &'a self,
+        ray: Ray,
+        dist: f64,
+        medium: Option<&'a Medium>,
+        interior: Interior,
+    ) -> (Color, Option<HitRecord<'a>>) {
+        let (mut ray, mut dist, mut medium) = (ray, dist, medium);
+        let mut transmittance = Color::new(1.0, 1.0, 1.0);
+        loop {
+            let hit = self.world.hit(&ray, 0.001, dist * (1.0 + 1e-6));
+            if let Some(m) = medium {
+                let length = hit
+                    .as_ref()
+                    .map_or(dist.min(self.escape_distance()), |h| h.t);
+                transmittance = transmittance.mul_element_wise(m.transmittance(length));
+            }
+            let length = hit.as_ref().map_or(dist, |h| h.t);
+            transmittance = transmittance.mul_element_wise(interior.absorption(length));
+            match hit {
+                Some(h) if h.material.is_boundary() => {
+                    medium = self.medium_behind(&h);
+                    dist -= h.t;
+                    ray = Ray::new(h.point, ray.dir());
                 }
+                hit => return (transmittance, hit),
+            }
+        }
+    }
+
+    /// Density of sampling `dir` from `from` with `sample_light`. `hit` is the surface found in
+    /// that direction, if any.
+    fn light_pdf(&self, from: P3, dir: V3, hit: Option<&HitRecord>) -> f64 {
+        let light = match hit {
+            Some(h) => self
+                .lights
+                .iter()
+                .find(|l| l.surface() == Some((h.object_id, h.leaf))),
+            None => self.lights.iter().find(|l| l.surface().is_none()),
+        };
+        light.map_or(0.0, |l| l.pdf(from, dir, hit) / self.lights.len() as f64)
+    }
+
+    /// Next-event estimation: direct lighting at `point` from one randomly chosen light,
+    /// weighted against BSDF sampling with multiple importance sampling. `bsdf` gives the BSDF
+    /// times the cosine term for light arriving from a direction, and the density with which
+    /// the path would continue in that direction. On surfaces, only lights above `normal` can
+    /// contribute. Light reaches `point` through `medium` and the dielectrics of `interior`.
+    fn sample_light<F>(
+        &self,
+        rng: &mut SampleRng,
+        point: P3,
+        normal: Option<V3>,
+        medium: Option<&Medium>,
+        interior: Interior,
+        bsdf: F,
+    ) -> Color
+    where
+        F: Fn(V3) -> (Color, f64),
+    {
+        if self.lights.is_empty() {
+            return Color::zero();
+        }
+        let light = &self.lights[rng.gen_range(0, self.lights.len())];
+        let sample = match light.sample(rng, point) {
+            Some(s) if normal.is_none_or(|n| s.dir.dot(n) > 0.0) => s,
+            _ => return Color::zero(),
+        };
+        let shadow_ray = Ray::new(point, sample.dir);
+        let radiance = match (
+            light.surface(),
+            self.shadow(shadow_ray, sample.dist, medium, interior),
+        ) {
+            (None, (transmittance, None)) => {
+                transmittance.mul_element_wise(self.sky.get_color(sample.dir))
+            }
+            (Some(surface), (transmittance, Some(h))) if (h.object_id, h.leaf) == surface => {
+                transmittance.mul_element_wise(h.material.emitted(&h))
             }
+            _ => return Color::zero(),
+        };
+        let light_pdf = sample.pdf / self.lights.len() as f64;
+        let (f, bsdf_pdf) = bsdf(sample.dir);
+        f.mul_element_wise(radiance) * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+    }
+}
+
+/// Depth of nesting of dielectrics past which the outermost ones are forgotten.
+const MAX_NESTING: usize = 4;
+
+/// Dielectrics enclosing a ray, innermost last, as the fraction of the light each one lets
+/// through per unit distance.
+#[derive(Copy, Clone, Debug)]
+struct Interior {
+    layers: [Color; MAX_NESTING],
+    len: usize,
+}
+
+impl Default for Interior {
+    fn default() -> Self {
+        Self {
+            layers: [Color::zero(); MAX_NESTING],
+            len: 0,
+        }
+    }
+}
+
+impl Interior {
+    fn enter(mut self, transmittance: Color) -> Self {
+        if self.len == MAX_NESTING {
+            self.layers.rotate_left(1);
+            self.len -= 1;
+        }
+        self.layers[self.len] = transmittance;
+        self.len += 1;
+        self
+    }
+
+    fn leave(mut self) -> Self {
+        self.len = self.len.saturating_sub(1);
+        self
+    }
+
+    /// Fraction of the light left after `distance` inside the innermost dielectric.
+    fn absorption(&self, distance: f64) -> Color {
+        match self.len {
+            0 => Color::new(1.0, 1.0, 1.0),
+            len => self.layers[len - 1].map(|c| c.powf(distance)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Accumulator;
+    use cgmath::Point3;
+
+    /// Bits of the pixels of a small render of `config` on a pool of `threads` threads.
+    fn render_on(config: config::Scene<Vec<config::Object>>, threads: usize) -> Vec<[u64; 3]> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let (width, height) = (16, 12);
+        let mut accumulator = Accumulator::new(width, height);
+        let scene = Scene::build(config).unwrap();
+        for block in pool.install(|| scene.run(width, height)) {
+            accumulator.add(&block);
         }
+        let pixels = accumulator.framebuffer().pixels;
+        pixels
+            .iter()
+            .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+            .collect()
+    }
+
+    #[test]
+    fn thread_count_independence() {
+        let config: config::Scene<_> = serde_yaml::from_str(
+            "
+camera: {pos: [0, 1, 3], look_at: [0, 0, -1], up: [0, 1, 0]}
+samples: 4
+tiles: {size: 4}
+progressive: true
+world:
+  - {type: Sphere, pos: [0, -100.5, -1], radius: 100, material: {type: Lambert, albedo: {color: [0.5, 0.5, 0.5]}}}
+  - {type: Sphere, pos: [0, 0, -1], radius: 0.5, material: {type: Dielectric, albedo: {color: [1, 1, 1]}, ior: 1.5}}
+  - {type: Sphere, pos: [1, 0, -1], radius: 0.5, material: {type: Metal, albedo: {color: [0.8, 0.6, 0.2]}, fuzz: 0.3}}
+",
+        )
+        .unwrap();
+        assert!(render_on(config.clone(), 1) == render_on(config, 4));
+    }
+
+    /// A glass shell around a bubble, through their centers. The interfaces are index-matched,
+    /// so that the ray goes straight through without reflecting.
+    #[test]
+    fn nested_dielectrics() {
+        let config = serde_yaml::from_str(
+            "
+camera:
+  pos: [0.0, 0.0, 3.0]
+  look_at: [0.0, 0.0, 0.0]
+  up: [0.0, 1.0, 0.0]
+  focus_distance: 3.0
+  aperture: 0.0
+  fov: 45.0
+sky:
+  type: Constant
+  color: [1.0, 1.0, 1.0]
+world:
+  - type: Sphere
+    pos: [0.0, 0.0, 0.0]
+    radius: 1.0
+    material: {type: Dielectric, albedo: {color: [0.5, 1.0, 1.0]}, ior: 1.0}
+  - type: Sphere
+    pos: [0.0, 0.0, 0.0]
+    radius: 0.5
+    material: {type: Dielectric, albedo: {color: [1.0, 0.5, 1.0]}, ior: 1.0}
+",
+        )
+        .unwrap();
+        let scene = Scene::build(config).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), -V3::unit_z());
+        let wavelength = spectrum::REFERENCE_WAVELENGTH;
+        let mut rng = sample_rng(1, 0, 0, 0);
+        let interior = Interior::default();
+        let color = scene.ray_color(&mut rng, ray, 10, None, wavelength, None, interior);
+        // The shell is crossed twice over 0.5 and the bubble over 1, each absorbing its own color
+        assert!(
+            (color - Color::new(0.5, 0.5, 1.0)).magnitude() < 1e-6,
+            "{:?}",
+            color
+        );
     }
 }
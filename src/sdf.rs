@@ -1,8 +1,6 @@
-use cgmath::{EuclideanSpace, InnerSpace, MetricSpace, Point3, Vector3};
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3};
 
-use crate::{config, P3, V3};
-use std::f64::EPSILON;
-use std::ops::Neg;
+use crate::{aabb::Aabb, config, P3, V3};
 
 #[derive(Copy, Clone, Debug)]
 pub struct Positioned<T> {
@@ -44,13 +42,6 @@ impl<T> Positioned<T> {
             value: mapper(self.value),
         }
     }
-
-    pub fn map_copy<U, F: FnOnce(&T) -> U>(&self, mapper: F) -> Positioned<U> {
-        Positioned {
-            pos: self.pos,
-            value: mapper(&self.value),
-        }
-    }
 }
 
 impl Positioned<SDF> {
@@ -58,9 +49,10 @@ impl Positioned<SDF> {
         let pos = pos - self.pos;
         self.value.sdf(from_vec3(pos))
     }
-    pub fn sdf_d(&self, pos: P3) -> V3 {
-        let pos = pos - self.pos;
-        self.value.sdf_d(from_vec3(pos))
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.value
+            .bounding_box()
+            .map(|b| b.translated(self.pos.to_vec()))
     }
 }
 
@@ -68,7 +60,7 @@ fn from_vec3<T>(vec: Vector3<T>) -> Point3<T> {
     Point3::new(vec.x, vec.y, vec.z)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum SDF {
     Sphere {
         radius: f64,
@@ -100,7 +92,7 @@ impl From<config::SDF> for SDF {
         match c {
             config::SDF::Sphere { radius } => Self::Sphere { radius },
             config::SDF::Plane { normal } => Self::Plane {
-                normal: normal.into(),
+                normal: V3::from(normal).normalize(),
             },
             config::SDF::Box { size } => Self::Box { size: size.into() },
             config::SDF::Rounding { sdf, amount } => Self::Rounding {
@@ -180,7 +172,7 @@ impl SDF {
             } => {
                 let d1 = left.sdf(pos);
                 let d2 = right.sdf(pos);
-                let h = (0.5 + 0.5 * (d2 - d1) / smooth).max(0.0).min(1.0);
+                let h = (0.5 + 0.5 * (d2 - d1) / smooth).clamp(0.0, 1.0);
                 lerp(d2, d1, h) - smooth * h * (1.0 - h)
             }
             Self::Intersection {
@@ -190,22 +182,52 @@ impl SDF {
             } => {
                 let d1 = left.sdf(pos);
                 let d2 = right.sdf(pos);
-                let h = (0.5 - 0.5 * (d2 - d1) / smooth).max(0.0).min(1.0);
+                let h = (0.5 - 0.5 * (d2 - d1) / smooth).clamp(0.0, 1.0);
                 lerp(d2, d1, h) + smooth * h * (1.0 - h)
             }
         }
     }
 
+    /// Outward unit normal of the surface closest to `pos`, i.e. the normalized gradient of
+    /// the distance field.
     pub fn sdf_d(&self, pos: P3) -> V3 {
         match self {
-            Self::Sphere { .. } => pos.to_vec().normalize().neg(),
+            Self::Sphere { .. } => pos.to_vec().normalize(),
             Self::Plane { normal } => *normal,
-            _ => V3::new(
-                self.sdf(pos + V3::unit_x() * EPSILON) - self.sdf(pos - V3::unit_x() * EPSILON),
-                self.sdf(pos + V3::unit_y() * EPSILON) - self.sdf(pos - V3::unit_y() * EPSILON),
-                self.sdf(pos + V3::unit_z() * EPSILON) - self.sdf(pos - V3::unit_z() * EPSILON),
-            )
-            .normalize(),
+            _ => {
+                let h = 1e-6;
+                let d = |axis: V3| self.sdf(pos + axis * h) - self.sdf(pos - axis * h);
+                V3::new(d(V3::unit_x()), d(V3::unit_y()), d(V3::unit_z())).normalize()
+            }
+        }
+    }
+
+    /// Conservative bounding box of the surface, or `None` if it is unbounded.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Self::Sphere { radius } => {
+                Some(Aabb::from_half_size(V3::new(*radius, *radius, *radius)))
+            }
+            Self::Plane { .. } => None,
+            Self::Box { size } => Some(Aabb::from_half_size(size.map(f64::abs))),
+            Self::Rounding { sdf, amount } => sdf.bounding_box().map(|b| b.grow(amount.max(0.0))),
+            // Smooth union can bulge out of both operands by at most `smooth / 4`
+            Self::Union {
+                left,
+                right,
+                smooth,
+            } => match (left.bounding_box(), right.bounding_box()) {
+                (Some(l), Some(r)) => Some(l.union(&r).grow(smooth.abs() / 4.0)),
+                _ => None,
+            },
+            // Smooth intersection only ever removes matter, so it stays within both operands
+            Self::Intersection { left, right, .. } => {
+                match (left.bounding_box(), right.bounding_box()) {
+                    (Some(l), Some(r)) => Some(l.intersection(&r)),
+                    (Some(b), None) | (None, Some(b)) => Some(b),
+                    (None, None) => None,
+                }
+            }
         }
     }
 }
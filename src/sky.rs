@@ -1,27 +1,300 @@
-use crate::{Color, V3};
-use serde::Deserialize;
-use cgmath::InnerSpace;
+use std::{convert::TryFrom, f64::consts::PI, sync::Arc};
 
-#[derive(Copy, Clone, Debug, Default, Deserialize)]
-pub struct Sky;
+use cgmath::{InnerSpace, Vector2};
+
+use crate::{
+    config,
+    texture::{Filter, Image, Wrap},
+    Color, V3,
+};
+
+/// Sun disc, seen as a cone of directions around `dir`.
+#[derive(Copy, Clone, Debug)]
+pub struct Sun {
+    pub dir: V3,
+    /// Cosine of the angular radius of the disc
+    pub cos_max: f64,
+    pub radiance: Color,
+}
+
+impl From<config::Sun> for Sun {
+    fn from(s: config::Sun) -> Self {
+        let (elevation, azimuth) = (s.elevation.to_radians(), s.azimuth.to_radians());
+        Self {
+            dir: V3::new(
+                elevation.cos() * azimuth.cos(),
+                elevation.sin(),
+                elevation.cos() * azimuth.sin(),
+            ),
+            cos_max: (s.angular_size.to_radians() / 2.0).cos(),
+            radiance: Color::from(s.color) * s.strength,
+        }
+    }
+}
+
+impl From<Sun> for config::Sun {
+    fn from(s: Sun) -> Self {
+        let strength = s.radiance.x.max(s.radiance.y).max(s.radiance.z);
+        let color = if strength > 0.0 {
+            s.radiance / strength
+        } else {
+            s.radiance
+        };
+        Self {
+            elevation: s.dir.y.clamp(-1.0, 1.0).asin().to_degrees(),
+            azimuth: s.dir.z.atan2(s.dir.x).to_degrees(),
+            angular_size: 2.0 * s.cos_max.acos().to_degrees(),
+            color: color.into(),
+            strength,
+        }
+    }
+}
+
+impl Sun {
+    fn get_color(&self, dir: V3) -> Option<Color> {
+        if dir.dot(self.dir) > self.cos_max {
+            Some(self.radiance)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Sky {
+    /// Vertical gradient from the horizon to the zenith
+    Gradient {
+        horizon: Color,
+        zenith: Color,
+        sun: Option<Sun>,
+    },
+    Constant {
+        color: Color,
+    },
+    /// Equirectangular environment map
+    Environment {
+        image: Arc<Image>,
+        /// Rotation around the vertical axis, in radians
+        rotation: f64,
+        intensity: f64,
+    },
+    /// Preetham et al. analytic daylight model
+    Physical {
+        sun: Sun,
+        turbidity: f64,
+        intensity: f64,
+        perez: Perez,
+    },
+}
+
+impl TryFrom<config::Sky> for Sky {
+    type Error = String;
+
+    fn try_from(s: config::Sky) -> Result<Self, String> {
+        Ok(match s {
+            config::Sky::Gradient {
+                horizon,
+                zenith,
+                sun,
+            } => Self::Gradient {
+                horizon: horizon.into(),
+                zenith: zenith.into(),
+                sun: sun.map(Sun::from),
+            },
+            config::Sky::Constant { color } => Self::Constant {
+                color: color.into(),
+            },
+            config::Sky::Environment {
+                filename,
+                rotation,
+                intensity,
+            } => Self::Environment {
+                image: Arc::new(Image::load(&filename).map_err(|e| {
+                    format!("cannot load environment {}: {}", filename.display(), e)
+                })?),
+                rotation: rotation.to_radians(),
+                intensity,
+            },
+            config::Sky::Physical {
+                sun,
+                turbidity,
+                intensity,
+            } => {
+                let sun = Sun::from(sun);
+                Self::Physical {
+                    perez: Perez::new(turbidity, sun.dir),
+                    sun,
+                    turbidity,
+                    intensity,
+                }
+            }
+        })
+    }
+}
+
+impl From<Sky> for config::Sky {
+    fn from(s: Sky) -> Self {
+        match s {
+            Sky::Gradient {
+                horizon,
+                zenith,
+                sun,
+            } => Self::Gradient {
+                horizon: horizon.into(),
+                zenith: zenith.into(),
+                sun: sun.map(Into::into),
+            },
+            Sky::Constant { color } => Self::Constant {
+                color: color.into(),
+            },
+            Sky::Environment {
+                image,
+                rotation,
+                intensity,
+            } => Self::Environment {
+                filename: image.filename.clone(),
+                rotation: rotation.to_degrees(),
+                intensity,
+            },
+            Sky::Physical {
+                sun,
+                turbidity,
+                intensity,
+                ..
+            } => Self::Physical {
+                sun: sun.into(),
+                turbidity,
+                intensity,
+            },
+        }
+    }
+}
 
 impl Sky {
     pub fn get_color(&self, dir: V3) -> Color {
-        let theta = dir.y.atan2(dir.x);
-        let phi = dir.z.acos(); // Assuming dir is constant
-        if dir.dot(self.sun_dir()) > 0.998 {
-            Color::new(100.0, 100.0, 100.0)
-        } else {
-            let t = 0.5 * (dir.y + 1.0);
-            Color::from(V3::new(1.0, 1.0, 1.0) * (1.0 - t) + V3::new(0.5, 0.7, 1.0) * t)
+        if let Some(color) = self.sun().and_then(|s| s.get_color(dir)) {
+            return color;
+        }
+        match self {
+            Self::Gradient {
+                horizon, zenith, ..
+            } => {
+                let t = 0.5 * (dir.y + 1.0);
+                horizon * (1.0 - t) + zenith * t
+            }
+            Self::Constant { color } => *color,
+            Self::Environment {
+                image,
+                rotation,
+                intensity,
+            } => {
+                let phi = dir.z.atan2(dir.x) + rotation;
+                let theta = dir.y.clamp(-1.0, 1.0).acos();
+                let uv = Vector2::new(phi / (2.0 * PI), 1.0 - theta / PI);
+                image.sample(uv, Wrap::Repeat, Filter::Bilinear) * *intensity
+            }
+            Self::Physical {
+                perez, intensity, ..
+            } => perez.get_color(dir) * *intensity,
+        }
+    }
+
+    pub fn sun(&self) -> Option<&Sun> {
+        match self {
+            Self::Gradient { sun, .. } => sun.as_ref(),
+            Self::Physical { sun, .. } => Some(sun),
+            Self::Constant { .. } | Self::Environment { .. } => None,
+        }
+    }
+}
+
+/// Perez luminance distribution parameters for the Y, x and y channels, along with the zenith
+/// values they are scaled by.
+#[derive(Copy, Clone, Debug)]
+pub struct Perez {
+    sun_dir: V3,
+    coeffs: [[f64; 5]; 3],
+    /// Zenith luminance (kcd/m²) and chromaticity, divided by the Perez function at the zenith
+    scale: [f64; 3],
+}
+
+impl Perez {
+    pub fn new(turbidity: f64, sun_dir: V3) -> Self {
+        let t = turbidity;
+        let coeffs = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let theta_s = sun_dir.y.clamp(0.0, 1.0).acos();
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (t2, th, th2, th3) = (t * t, theta_s, theta_s * theta_s, theta_s.powi(3));
+        let zenith_x = (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) * t2
+            + (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) * t
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_yc = (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) * t2
+            + (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) * t
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        let zenith = [zenith_y.max(0.0), zenith_x, zenith_yc];
+        let mut scale = [0.0; 3];
+        for i in 0..3 {
+            scale[i] = zenith[i] / perez(&coeffs[i], 1.0, theta_s.cos());
+        }
+        Self {
+            sun_dir,
+            coeffs,
+            scale,
         }
     }
 
-    pub fn sun_dir(&self) -> V3 {
-        V3::new(1.0, 1.0, 1.0).normalize()
+    /// Linear sRGB radiance, where a luminance of 10 kcd/m² maps to 1.
+    pub fn get_color(&self, dir: V3) -> Color {
+        let cos_theta = dir.y.max(0.01);
+        let cos_gamma = dir.dot(self.sun_dir).clamp(-1.0, 1.0);
+        let lum = self.scale[0] * perez(&self.coeffs[0], cos_theta, cos_gamma);
+        let x = self.scale[1] * perez(&self.coeffs[1], cos_theta, cos_gamma);
+        let y = self.scale[2] * perez(&self.coeffs[2], cos_theta, cos_gamma);
+        xyy_to_rgb(x, y, lum / 10.0)
     }
+}
+
+fn perez(c: &[f64; 5], cos_theta: f64, cos_gamma: f64) -> f64 {
+    let gamma = cos_gamma.acos();
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
 
-    pub fn sun_color(&self) -> Color {
-        Color::new(1.0, 1.0, 1.0)
+fn xyy_to_rgb(x: f64, y: f64, lum: f64) -> Color {
+    if y <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0);
     }
+    let cx = x * lum / y;
+    let cz = (1.0 - x - y) * lum / y;
+    Color::new(
+        3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * lum + 1.0570 * cz,
+    )
+    .map(|c| c.max(0.0))
 }
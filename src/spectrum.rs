@@ -0,0 +1,106 @@
+//! Conversion of single-wavelength samples to RGB, for the spectral rendering mode.
+//!
+//! Each camera sample traces its path at one wavelength, and the radiance it finds is weighted
+//! by the RGB response of that wavelength. The weights average to 1 over the visible range, so
+//! scenes without wavelength-dependent materials converge to the same image as in RGB mode.
+
+use crate::Color;
+
+/// Visible range in nanometers, sampled uniformly.
+pub const MIN_WAVELENGTH: f64 = 380.0;
+pub const MAX_WAVELENGTH: f64 = 780.0;
+
+/// Wavelength at which indices of refraction are given for RGB rendering: the sodium D line.
+pub const REFERENCE_WAVELENGTH: f64 = 587.6;
+
+/// Integral of each RGB component of the matching functions over the visible range.
+const RGB_INTEGRAL: [f64; 3] = [128.362_685_512, 101.548_635_991, 97.049_555_626];
+
+/// Wavelength for the uniform random number `u`.
+pub fn sample_wavelength(u: f64) -> f64 {
+    MIN_WAVELENGTH + u * (MAX_WAVELENGTH - MIN_WAVELENGTH)
+}
+
+/// Factor turning radiance traced at `wavelength` into an RGB estimate. Components may be
+/// negative for wavelengths outside of the sRGB gamut.
+pub fn rgb_weight(wavelength: f64) -> Color {
+    let rgb = xyz_to_rgb(cie_xyz(wavelength));
+    let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+    Color::new(
+        rgb.x * range / RGB_INTEGRAL[0],
+        rgb.y * range / RGB_INTEGRAL[1],
+        rgb.z * range / RGB_INTEGRAL[2],
+    )
+}
+
+/// CIE 1931 color matching functions, from the multi-lobe fit of Wyman, Sloan and Shirley,
+/// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions" (2013).
+fn cie_xyz(wavelength: f64) -> Color {
+    let g = |mu: f64, below: f64, above: f64| {
+        let sigma = if wavelength < mu { below } else { above };
+        let t = (wavelength - mu) / sigma;
+        (-0.5 * t * t).exp()
+    };
+    Color::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// Linear sRGB, with a D65 white point.
+fn xyz_to_rgb(xyz: Color) -> Color {
+    Color::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use cgmath::Zero;
+
+    #[test]
+    fn weights_average_to_white() {
+        let steps = 100_000;
+        let total = (0..steps).fold(Color::zero(), |total, i| {
+            total + rgb_weight(sample_wavelength((f64::from(i) + 0.5) / f64::from(steps)))
+        });
+        let average = total / f64::from(steps);
+        for c in &[average.x, average.y, average.z] {
+            assert!((c - 1.0).abs() < 1e-6, "{:?}", average);
+        }
+    }
+
+    #[test]
+    fn dispersion() {
+        // N-BK7 glass
+        let sellmeier = config::Ior::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        };
+        assert!((sellmeier.at(REFERENCE_WAVELENGTH) - 1.5168).abs() < 1e-4);
+        let cauchy = config::Ior::Cauchy {
+            a: 1.5046,
+            b: 0.0042,
+        };
+        assert!((cauchy.at(REFERENCE_WAVELENGTH) - 1.5168).abs() < 1e-3);
+        for ior in &[sellmeier, cauchy] {
+            // Blue light bends more than red
+            assert!(ior.at(450.0) > ior.at(650.0));
+        }
+    }
+
+    #[test]
+    fn wavelengths_have_their_color() {
+        let red = rgb_weight(650.0);
+        assert!(red.x > 0.0 && red.x > red.y && red.x > red.z);
+        let green = rgb_weight(530.0);
+        assert!(green.y > green.x && green.y > green.z);
+        let blue = rgb_weight(450.0);
+        assert!(blue.z > blue.x && blue.z > blue.y);
+    }
+}
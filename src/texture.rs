@@ -0,0 +1,360 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use cgmath::{InnerSpace, Vector2};
+
+use crate::{config, Color, P3, V3};
+
+type V2 = Vector2<f64>;
+
+/// Texture coordinates of a hit point, as computed by the object that was hit.
+#[derive(Copy, Clone, Debug)]
+pub enum TexCoords {
+    Uv(V2),
+    /// Local-space position and normal, for objects without a natural parametrization; textures
+    /// are projected along the 3 axes and blended according to the normal.
+    Triplanar {
+        pos: P3,
+        normal: V3,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl From<config::Wrap> for Wrap {
+    fn from(w: config::Wrap) -> Self {
+        match w {
+            config::Wrap::Repeat => Self::Repeat,
+            config::Wrap::Clamp => Self::Clamp,
+            config::Wrap::Mirror => Self::Mirror,
+        }
+    }
+}
+
+impl From<Wrap> for config::Wrap {
+    fn from(w: Wrap) -> Self {
+        match w {
+            Wrap::Repeat => Self::Repeat,
+            Wrap::Clamp => Self::Clamp,
+            Wrap::Mirror => Self::Mirror,
+        }
+    }
+}
+
+impl Wrap {
+    fn apply(&self, i: i64, size: usize) -> usize {
+        let size = size as i64;
+        let i = match self {
+            Self::Repeat => i.rem_euclid(size),
+            Self::Clamp => i.clamp(0, size - 1),
+            Self::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 1 - i
+                }
+            }
+        };
+        i as usize
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+impl From<config::Filter> for Filter {
+    fn from(f: config::Filter) -> Self {
+        match f {
+            config::Filter::Nearest => Self::Nearest,
+            config::Filter::Bilinear => Self::Bilinear,
+        }
+    }
+}
+
+impl From<Filter> for config::Filter {
+    fn from(f: Filter) -> Self {
+        match f {
+            Filter::Nearest => Self::Nearest,
+            Filter::Bilinear => Self::Bilinear,
+        }
+    }
+}
+
+/// Image loaded in memory, in linear color space.
+pub struct Image {
+    pub filename: PathBuf,
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 3]>,
+}
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Image")
+            .field("filename", &self.filename)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+impl Image {
+    /// Load an image file. 8 and 16-bit images are assumed to be sRGB encoded, while float
+    /// images (HDR, EXR) are assumed to be linear.
+    pub fn load<P: AsRef<Path>>(filename: P) -> image::ImageResult<Self> {
+        let filename = filename.as_ref();
+        let img = image::open(filename)?;
+        let is_float = matches!(
+            img,
+            image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_)
+        );
+        let img = img.into_rgb32f();
+        let pixels = img
+            .pixels()
+            .map(|p| {
+                if is_float {
+                    p.0
+                } else {
+                    p.0.map(srgb_to_linear)
+                }
+            })
+            .collect();
+        Ok(Self {
+            filename: filename.to_path_buf(),
+            width: img.width() as usize,
+            height: img.height() as usize,
+            pixels,
+        })
+    }
+
+    pub fn texel(&self, x: usize, y: usize) -> Color {
+        let [r, g, b] = self.pixels[y * self.width + x];
+        Color::new(r as f64, g as f64, b as f64)
+    }
+
+    /// Sample the image at `uv`, with (0, 0) being the bottom-left corner of the image.
+    pub fn sample(&self, uv: V2, wrap: Wrap, filter: Filter) -> Color {
+        let x = uv.x * self.width as f64 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f64 - 0.5;
+        match filter {
+            Filter::Nearest => self.texel(
+                wrap.apply(x.round() as i64, self.width),
+                wrap.apply(y.round() as i64, self.height),
+            ),
+            Filter::Bilinear => {
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let tx = |dx| wrap.apply(x0 + dx, self.width);
+                let ty = |dy| wrap.apply(y0 + dy, self.height);
+                let top = self.texel(tx(0), ty(0)) * (1.0 - fx) + self.texel(tx(1), ty(0)) * fx;
+                let bottom = self.texel(tx(0), ty(1)) * (1.0 - fx) + self.texel(tx(1), ty(1)) * fx;
+                top * (1.0 - fy) + bottom * fy
+            }
+        }
+    }
+}
+
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Texture {
+    Constant(Color),
+    Image {
+        image: Arc<Image>,
+        wrap: Wrap,
+        filter: Filter,
+    },
+    Checker {
+        even: Color,
+        odd: Color,
+        scale: f64,
+    },
+    Noise {
+        low: Color,
+        high: Color,
+        scale: f64,
+        octaves: u32,
+    },
+}
+
+impl TryFrom<config::ColorInput> for Texture {
+    type Error = String;
+
+    fn try_from(c: config::ColorInput) -> Result<Self, String> {
+        Ok(match c {
+            config::ColorInput::Color { color } => Self::Constant(color.into()),
+            config::ColorInput::Texture {
+                filename,
+                wrap,
+                filter,
+            } => {
+                Self::Image {
+                    image: Arc::new(Image::load(&filename).map_err(|e| {
+                        format!("cannot load texture {}: {}", filename.display(), e)
+                    })?),
+                    wrap: wrap.into(),
+                    filter: filter.into(),
+                }
+            }
+            config::ColorInput::Checker { even, odd, scale } => Self::Checker {
+                even: even.into(),
+                odd: odd.into(),
+                scale,
+            },
+            config::ColorInput::Noise {
+                low,
+                high,
+                scale,
+                octaves,
+            } => Self::Noise {
+                low: low.into(),
+                high: high.into(),
+                scale,
+                octaves,
+            },
+        })
+    }
+}
+
+impl From<Texture> for config::ColorInput {
+    fn from(t: Texture) -> Self {
+        match t {
+            Texture::Constant(color) => Self::Color {
+                color: color.into(),
+            },
+            Texture::Image {
+                image,
+                wrap,
+                filter,
+            } => Self::Texture {
+                filename: image.filename.clone(),
+                wrap: wrap.into(),
+                filter: filter.into(),
+            },
+            Texture::Checker { even, odd, scale } => Self::Checker {
+                even: even.into(),
+                odd: odd.into(),
+                scale,
+            },
+            Texture::Noise {
+                low,
+                high,
+                scale,
+                octaves,
+            } => Self::Noise {
+                low: low.into(),
+                high: high.into(),
+                scale,
+                octaves,
+            },
+        }
+    }
+}
+
+impl Texture {
+    pub fn sample(&self, coords: &TexCoords) -> Color {
+        match (self, coords) {
+            (Self::Constant(color), _) => *color,
+            (_, TexCoords::Uv(uv)) => self.sample_uv(*uv),
+            (_, TexCoords::Triplanar { pos, normal }) => {
+                // Sharpen the blend so that each projection dominates on the faces it suits
+                let w = normal.map(|x| x.abs().powi(4));
+                let w = w / (w.x + w.y + w.z);
+                self.sample_uv(V2::new(pos.z, pos.y)) * w.x
+                    + self.sample_uv(V2::new(pos.x, pos.z)) * w.y
+                    + self.sample_uv(V2::new(pos.x, pos.y)) * w.z
+            }
+        }
+    }
+
+    fn sample_uv(&self, uv: V2) -> Color {
+        match self {
+            Self::Constant(color) => *color,
+            Self::Image {
+                image,
+                wrap,
+                filter,
+            } => image.sample(uv, *wrap, *filter),
+            Self::Checker { even, odd, scale } => {
+                let p = uv * *scale;
+                if (p.x.floor() + p.y.floor()).rem_euclid(2.0) < 1.0 {
+                    *even
+                } else {
+                    *odd
+                }
+            }
+            Self::Noise {
+                low,
+                high,
+                scale,
+                octaves,
+            } => {
+                let t = 0.5 + 0.5 * fbm(uv * *scale, *octaves);
+                low * (1.0 - t) + high * t
+            }
+        }
+    }
+}
+
+/// Fractal sum of gradient noise octaves, in [-1, 1].
+fn fbm(p: V2, octaves: u32) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut p = p;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * gradient_noise(p);
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    sum / total
+}
+
+fn hash(x: i64, y: i64) -> u64 {
+    let mut h = (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+    h ^= h >> 33;
+    h
+}
+
+fn gradient(x: i64, y: i64) -> V2 {
+    let angle = (hash(x, y) >> 11) as f64 / (1u64 << 53) as f64 * std::f64::consts::TAU;
+    V2::new(angle.cos(), angle.sin())
+}
+
+/// 2D Perlin-style gradient noise, roughly in [-1, 1].
+fn gradient_noise(p: V2) -> f64 {
+    let (x0, y0) = (p.x.floor(), p.y.floor());
+    let f = V2::new(p.x - x0, p.y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let corner =
+        |dx: i64, dy: i64| gradient(x0 + dx, y0 + dy).dot(f - V2::new(dx as f64, dy as f64));
+    let (u, v) = (fade(f.x), fade(f.y));
+    let bottom = corner(0, 0) * (1.0 - u) + corner(1, 0) * u;
+    let top = corner(0, 1) * (1.0 - u) + corner(1, 1) * u;
+    std::f64::consts::SQRT_2 * (bottom * (1.0 - v) + top * v)
+}
@@ -0,0 +1,225 @@
+use crate::config::{self, TileOrder};
+
+/// Rectangle of pixels rendered as a unit, with `y` counted from the top of the image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    /// Pixel coordinates covered by the tile, row by row.
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let Self {
+            x,
+            y,
+            width,
+            height,
+        } = *self;
+        (y..y + height).flat_map(move |j| (x..x + width).map(move |i| (i, j)))
+    }
+}
+
+/// Rectangle of a `width` x `height` image covered by `region`, clamped to the image, or the
+/// whole image without a region.
+pub fn region(width: u32, height: u32, region: Option<config::Region>) -> Tile {
+    let (x0, y0, x1, y1) = match region {
+        None => (0, 0, width, height),
+        Some(config::Region::Pixels {
+            x,
+            y,
+            width: w,
+            height: h,
+        }) => (x, y, x.saturating_add(w), y.saturating_add(h)),
+        Some(config::Region::Border {
+            left,
+            top,
+            right,
+            bottom,
+        }) => {
+            let scale = |f: f64, size: u32| (f.clamp(0.0, 1.0) * size as f64).round() as u32;
+            (
+                scale(left, width),
+                scale(top, height),
+                scale(right, width),
+                scale(bottom, height),
+            )
+        }
+    };
+    let (x0, y0) = (x0.min(width), y0.min(height));
+    Tile {
+        x: x0,
+        y: y0,
+        width: x1.min(width).saturating_sub(x0),
+        height: y1.min(height).saturating_sub(y0),
+    }
+}
+
+/// Split `area` into tiles, listed in the order they should be rendered.
+pub fn tiles(area: Tile, settings: config::Tiles) -> Vec<Tile> {
+    let Tile {
+        x: x0,
+        y: y0,
+        width,
+        height,
+    } = area;
+    let size = settings.size.max(1);
+    let (cols, rows) = (width.div_ceil(size), height.div_ceil(size));
+    let order = match settings.order {
+        TileOrder::Scanline => (0..rows)
+            .flat_map(|r| (0..cols).map(move |c| (c, r)))
+            .collect(),
+        TileOrder::Spiral => spiral(cols, rows),
+        TileOrder::Hilbert => {
+            let n = cols.max(rows).next_power_of_two();
+            let mut cells: Vec<_> = (0..rows)
+                .flat_map(|r| (0..cols).map(move |c| (c, r)))
+                .collect();
+            cells.sort_by_key(|&(c, r)| hilbert_index(n, c, r));
+            cells
+        }
+    };
+    order
+        .into_iter()
+        .map(|(c, r)| Tile {
+            x: x0 + c * size,
+            y: y0 + r * size,
+            width: size.min(width - c * size),
+            height: size.min(height - r * size),
+        })
+        .collect()
+}
+
+/// Cells of a `cols` x `rows` grid, walking a square spiral out from the center.
+fn spiral(cols: u32, rows: u32) -> Vec<(u32, u32)> {
+    let total = (cols * rows) as usize;
+    let mut cells = Vec::with_capacity(total);
+    let (mut x, mut y) = ((cols as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let (mut dx, mut dy) = (1, 0);
+    let mut leg = 1;
+    while cells.len() < total {
+        // Legs grow by one every two turns: right 1, down 1, left 2, up 2, right 3...
+        for _ in 0..2 {
+            for _ in 0..leg {
+                if (0..cols as i64).contains(&x) && (0..rows as i64).contains(&y) {
+                    cells.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            let turned = (-dy, dx);
+            dx = turned.0;
+            dy = turned.1;
+        }
+        leg += 1;
+    }
+    cells
+}
+
+/// Distance along the Hilbert curve filling an `n` x `n` grid, `n` being a power of two.
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += u64::from(s) * u64::from(s) * u64::from((3 * rx) ^ ry);
+        // Rotate the quadrant so that the curve inside it has the right orientation
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_image_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            for &(width, height, size) in &[(100, 60, 16), (64, 64, 32), (7, 300, 5), (1, 1, 8)] {
+                let mut covered = vec![0; (width * height) as usize];
+                let area = region(width, height, None);
+                for tile in tiles(area, config::Tiles { size, order }) {
+                    for (i, j) in tile.pixels() {
+                        covered[(j * width + i) as usize] += 1;
+                    }
+                }
+                assert!(
+                    covered.iter().all(|&c| c == 1),
+                    "{:?} {}x{}",
+                    order,
+                    width,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn region_rectangles() {
+        let pixels = config::Region::Pixels {
+            x: 10,
+            y: 20,
+            width: 30,
+            height: 100,
+        };
+        let expected = Tile {
+            x: 10,
+            y: 20,
+            width: 30,
+            height: 40,
+        };
+        assert_eq!(region(100, 60, Some(pixels)), expected);
+        let border = config::Region::Border {
+            left: 0.1,
+            top: 0.25,
+            right: 0.4,
+            bottom: 1.5,
+        };
+        let expected = Tile {
+            x: 10,
+            y: 15,
+            width: 30,
+            height: 45,
+        };
+        assert_eq!(region(100, 60, Some(border)), expected);
+        let area = region(100, 60, Some(pixels));
+        let pixels: Vec<_> = tiles(area, config::Tiles::default())
+            .iter()
+            .flat_map(Tile::pixels)
+            .collect();
+        assert_eq!(pixels.len(), 30 * 40);
+        assert!(pixels
+            .iter()
+            .all(|&(i, j)| (10..40).contains(&i) && (20..60).contains(&j)));
+    }
+
+    #[test]
+    fn hilbert_steps_to_neighbours() {
+        let order: Vec<_> = tiles(
+            region(64, 64, None),
+            config::Tiles {
+                size: 8,
+                order: TileOrder::Hilbert,
+            },
+        )
+        .into_iter()
+        .map(|t| (t.x as i64 / 8, t.y as i64 / 8))
+        .collect();
+        for w in order.windows(2) {
+            assert_eq!((w[0].0 - w[1].0).abs() + (w[0].1 - w[1].1).abs(), 1);
+        }
+    }
+}
@@ -1,35 +1,64 @@
+use crate::aabb::Aabb;
 use crate::material::Material;
 use crate::ray::Ray;
-use cgmath::{InnerSpace, Point3, Vector3, Array};
+use crate::texture::TexCoords;
+use cgmath::{InnerSpace, Point3, Vector3};
 
 #[derive(Copy, Clone, Debug)]
-pub struct HitRecord {
+pub struct HitRecord<'a> {
     pub point: Point3<f64>,
     pub normal: Vector3<f64>,
+    /// Normal of the surface itself, facing either side. It only differs from the shading
+    /// `normal` on meshes with vertex normals.
+    pub geometric_normal: Vector3<f64>,
     pub t: f64,
     pub front_face: bool,
-    pub material: Material,
+    pub uv: TexCoords,
+    pub material: &'a Material,
+    /// Index of the object which was hit
+    pub object_id: usize,
+    /// Index of the leaf object which was hit among those of the group `object_id`, in the
+    /// order of their definition, 0 outside of groups
+    pub leaf: usize,
+    /// Index of the material of the object, shared by objects with identical materials
+    pub material_id: usize,
 }
 
-impl HitRecord {
-    pub fn from_hit(ray: &Ray, normal: Vector3<f64>, t: f64, material: Material) -> Self {
+impl<'a> HitRecord<'a> {
+    pub fn from_hit(
+        ray: &Ray,
+        normal: Vector3<f64>,
+        t: f64,
+        uv: TexCoords,
+        material: &'a Material,
+    ) -> Self {
         let front_face = ray.dir().dot(normal) <= 0.0;
         Self {
             t,
             normal: if front_face { normal } else { -normal },
+            geometric_normal: normal,
             point: ray.at(t),
             front_face,
+            uv,
             material,
+            object_id: 0,
+            leaf: 0,
+            material_id: 0,
         }
     }
 }
 
 pub trait Hittable: Sync {
-    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord<'_>>;
+}
+
+pub trait Bounded {
+    /// World-space bounding box, or `None` if the object is unbounded.
+    fn bounding_box(&self) -> Option<Aabb>;
 }
 
 /*impl<T: Hittable> Hittable for [T] {
-    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord<'_>> {
         self.iter()
             .filter_map(|obj| obj.hit(ray, tmin, tmax))
             .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap())
@@ -37,12 +66,10 @@ pub trait Hittable: Sync {
 }*/
 
 impl<T: Hittable> Hittable for Vec<T> {
-    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord<'_>> {
         self.iter()
             .filter_map(|obj| obj.hit(ray, tmin, tmax))
             .filter(|h| h.t.is_finite())
-            .min_by(|a, b| {
-                a.t.partial_cmp(&b.t).unwrap()
-            })
+            .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap())
     }
 }
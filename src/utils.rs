@@ -1,24 +1,52 @@
 use crate::V3;
 use cgmath::{InnerSpace, Vector2};
-use rand::rngs::ThreadRng;
 use rand::Rng;
+use rand_pcg::Pcg32;
 
-pub fn random_vector(rng: &mut ThreadRng) -> V3 {
-    let distr = rand::distributions::Uniform::new(-1.0, 1.0);
-    V3::new(rng.sample(distr), rng.sample(distr), rng.sample(distr)).normalize()
+/// Random number generator used for rendering. Each sample gets its own generator, seeded from
+/// the scene seed and the sample coordinates, so that renders are reproducible no matter how
+/// the work is split between threads.
+pub type SampleRng = Pcg32;
+
+/// Generator for sample number `sample` of pixel (`x`, `y`).
+pub fn sample_rng(seed: u64, x: u32, y: u32, sample: u32) -> SampleRng {
+    let pixel = (u64::from(y) << 32) | u64::from(x);
+    Pcg32::new(mix(seed ^ mix(pixel)), u64::from(sample))
 }
 
-pub fn random_in_hemisphere(rng: &mut ThreadRng, normal: V3) -> V3 {
-    let v = random_vector(rng);
-    if v.dot(normal) > 0.0 {
-        v
-    } else {
-        -v
+/// SplitMix64 finalizer, to decorrelate seeds which differ by few bits.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Uniformly distributed random unit vector.
+pub fn random_vector(rng: &mut SampleRng) -> V3 {
+    let distr = rand::distributions::Uniform::new(-1.0, 1.0);
+    loop {
+        let v = V3::new(rng.sample(distr), rng.sample(distr), rng.sample(distr));
+        let len2 = v.magnitude2();
+        if len2 > 1e-12 && len2 <= 1.0 {
+            return v / len2.sqrt();
+        }
     }
 }
 
-pub fn random_in_unit_disk(rng: &mut ThreadRng) -> V3 {
+pub fn random_in_unit_disk(rng: &mut SampleRng) -> V3 {
     let distrib = rand::distributions::Uniform::new(-1.0, 1.0);
     let p2 = Vector2::new(rng.sample(distrib), rng.sample(distrib));
     p2.normalize().extend(0.0)
-}
\ No newline at end of file
+}
+
+/// Two unit vectors forming an orthonormal basis with `n`.
+pub fn orthonormal_basis(n: V3) -> (V3, V3) {
+    let reference = if n.x.abs() > 0.9 {
+        V3::unit_y()
+    } else {
+        V3::unit_x()
+    };
+    let t = n.cross(reference).normalize();
+    (t, n.cross(t))
+}
@@ -0,0 +1,705 @@
+//! Checks of the values of a scene, which deserialization alone lets through.
+
+// Comparisons are negated on purpose, so that NaNs fail them
+#![allow(clippy::neg_cmp_op_on_partial_ord)]
+
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use cgmath::{InnerSpace, Matrix, Matrix4};
+
+use crate::{
+    config, objects,
+    spectrum::{MAX_WAVELENGTH, MIN_WAVELENGTH},
+    V3,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The scene can be rendered, but probably not as intended
+    Warning,
+    /// The scene cannot be rendered
+    Error,
+}
+
+/// Problem with a value of the scene, found at `path`, such as `world[3].material.ior`.
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// All the problems found in `scene`.
+pub fn validate(scene: &config::Scene<Vec<config::Object>>) -> Vec<Problem> {
+    let mut checker = Checker {
+        definitions: &scene.objects,
+        problems: vec![],
+    };
+    checker.scene(scene);
+    checker.problems
+}
+
+struct Checker<'a> {
+    definitions: &'a BTreeMap<String, config::Object>,
+    problems: Vec<Problem>,
+}
+
+fn field(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn index(path: &str, i: usize) -> String {
+    format!("{}[{}]", path, i)
+}
+
+impl Checker<'_> {
+    fn error(&mut self, path: String, message: impl Into<String>) {
+        self.problems.push(Problem {
+            severity: Severity::Error,
+            path,
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, path: String, message: impl Into<String>) {
+        self.problems.push(Problem {
+            severity: Severity::Warning,
+            path,
+            message: message.into(),
+        });
+    }
+
+    fn positive(&mut self, path: String, value: f64) {
+        if !(value > 0.0) {
+            self.error(path, format!("must be positive, got {}", value));
+        }
+    }
+
+    fn non_negative(&mut self, path: String, value: f64) {
+        if !(value >= 0.0) {
+            self.error(path, format!("must not be negative, got {}", value));
+        }
+    }
+
+    fn unit(&mut self, path: String, value: f64) {
+        if !(0.0..=1.0).contains(&value) {
+            self.error(path, format!("must be between 0 and 1, got {}", value));
+        }
+    }
+
+    fn direction(&mut self, path: String, value: [f64; 3]) {
+        if !(V3::from(value).magnitude2() > 0.0) {
+            self.error(path, "must not be a zero vector");
+        }
+    }
+
+    fn file(&mut self, path: String, file: &Path) {
+        if !file.is_file() {
+            self.error(path, format!("cannot find file {}", file.display()));
+        }
+    }
+
+    /// Color of a light, or of a surface if `albedo` is set, which must then reflect less
+    /// light than it receives.
+    fn color(&mut self, path: String, color: [f64; 3], albedo: bool) {
+        if color.iter().any(|c| !(*c >= 0.0)) {
+            self.error(
+                path,
+                format!("must not have negative components, got {:?}", color),
+            );
+        } else if albedo && color.iter().any(|c| *c > 1.0) {
+            self.warning(
+                path,
+                format!("reflects more light than it receives, got {:?}", color),
+            );
+        }
+    }
+
+    fn scene(&mut self, scene: &config::Scene<Vec<config::Object>>) {
+        if scene.samples == 0 {
+            self.error("samples".to_string(), "must be at least 1");
+        }
+        if scene.bounces == 0 {
+            self.warning(
+                "bounces".to_string(),
+                "no light is gathered without bounces",
+            );
+        }
+        if scene.tiles.size == 0 {
+            self.error("tiles.size".to_string(), "must be at least 1");
+        }
+        match scene.region {
+            Some(config::Region::Pixels { width, height, .. }) if width == 0 || height == 0 => {
+                self.error("region".to_string(), "is empty");
+            }
+            Some(config::Region::Border {
+                left,
+                top,
+                right,
+                bottom,
+            }) => {
+                if !(left < right && top < bottom) {
+                    self.error("region".to_string(), "is empty");
+                }
+                if [left, top, right, bottom]
+                    .iter()
+                    .any(|f| !(0.0..=1.0).contains(f))
+                {
+                    self.warning(
+                        "region".to_string(),
+                        "fractions of the image size are clamped between 0 and 1",
+                    );
+                }
+            }
+            _ => {}
+        }
+        if scene.crop && scene.region.is_none() {
+            self.warning("crop".to_string(), "has no effect without a `region`");
+        }
+        if let Some(atmosphere) = scene.atmosphere {
+            let config::Atmosphere {
+                absorption,
+                scattering,
+                anisotropy,
+                distance,
+            } = atmosphere;
+            self.medium("atmosphere", absorption, scattering, anisotropy);
+            self.positive("atmosphere.distance".to_string(), distance);
+        }
+        if let Some(adaptive) = scene.adaptive {
+            if adaptive.min_samples > scene.samples {
+                self.warning(
+                    "adaptive.min_samples".to_string(),
+                    "is more than `samples`, so no pixel stops early",
+                );
+            }
+            if !(adaptive.threshold > 0.0) {
+                self.warning(
+                    "adaptive.threshold".to_string(),
+                    "no pixel ever converges below a threshold of 0",
+                );
+            }
+        }
+        for (i, aov) in scene.aovs.iter().enumerate() {
+            if scene.aovs[..i].contains(aov) {
+                self.warning(
+                    index("aovs", i),
+                    format!("`{}` is listed twice", aov.name()),
+                );
+            }
+        }
+        if let Some(denoise) = scene.denoise {
+            if denoise.radius == 0 {
+                self.warning("denoise.radius".to_string(), "leaves the image unchanged");
+            }
+            self.positive("denoise.sigma_spatial".to_string(), denoise.sigma_spatial);
+            self.positive("denoise.sigma_color".to_string(), denoise.sigma_color);
+            self.positive("denoise.sigma_normal".to_string(), denoise.sigma_normal);
+            self.positive("denoise.sigma_albedo".to_string(), denoise.sigma_albedo);
+        }
+        self.camera(&scene.camera);
+        self.sky(&scene.sky);
+        for (name, object) in scene.objects.iter() {
+            self.object(&field("objects", name), object);
+        }
+        if scene.world.is_empty() {
+            self.warning("world".to_string(), "is empty");
+        }
+        for (i, object) in scene.world.iter().enumerate() {
+            self.object(&index("world", i), object);
+        }
+    }
+
+    fn camera(&mut self, camera: &config::Camera) {
+        let dir = V3::from(camera.look_at) - V3::from(camera.pos);
+        if !(dir.magnitude2() > 0.0) {
+            self.error(
+                "camera.look_at".to_string(),
+                "must be a different point than `camera.pos`",
+            );
+        } else if !(V3::from(camera.up).cross(dir.normalize()).magnitude2() > 1e-12) {
+            self.error(
+                "camera.up".to_string(),
+                "must not be zero or parallel to the view direction",
+            );
+        }
+        if !(camera.fov > 0.0 && camera.fov < 180.0) {
+            self.error(
+                "camera.fov".to_string(),
+                format!("must be between 0 and 180 degrees, got {}", camera.fov),
+            );
+        }
+        self.non_negative("camera.aperture".to_string(), camera.aperture);
+        if let Some(focus_distance) = camera.focus_distance {
+            self.positive("camera.focus_distance".to_string(), focus_distance);
+        }
+    }
+
+    fn sun(&mut self, path: &str, sun: &config::Sun) {
+        if !(sun.angular_size > 0.0 && sun.angular_size < 180.0) {
+            self.error(
+                field(path, "angular_size"),
+                format!(
+                    "must be between 0 and 180 degrees, got {}",
+                    sun.angular_size
+                ),
+            );
+        }
+        self.color(field(path, "color"), sun.color, false);
+        self.non_negative(field(path, "strength"), sun.strength);
+    }
+
+    fn sky(&mut self, sky: &config::Sky) {
+        match sky {
+            config::Sky::Gradient {
+                horizon,
+                zenith,
+                sun,
+            } => {
+                self.color("sky.horizon".to_string(), *horizon, false);
+                self.color("sky.zenith".to_string(), *zenith, false);
+                if let Some(sun) = sun {
+                    self.sun("sky.sun", sun);
+                }
+            }
+            config::Sky::Constant { color } => self.color("sky.color".to_string(), *color, false),
+            config::Sky::Environment {
+                filename,
+                intensity,
+                ..
+            } => {
+                self.file("sky.filename".to_string(), filename);
+                self.non_negative("sky.intensity".to_string(), *intensity);
+            }
+            config::Sky::Physical {
+                sun,
+                turbidity,
+                intensity,
+            } => {
+                self.sun("sky.sun", sun);
+                // The fit of the Preetham model only holds for these
+                if !(2.0..=10.0).contains(turbidity) {
+                    self.warning(
+                        "sky.turbidity".to_string(),
+                        format!("should be between 2 and 10, got {}", turbidity),
+                    );
+                }
+                self.non_negative("sky.intensity".to_string(), *intensity);
+            }
+        }
+    }
+
+    fn color_input(&mut self, path: &str, input: &config::ColorInput, albedo: bool) {
+        match input {
+            config::ColorInput::Color { color } => self.color(field(path, "color"), *color, albedo),
+            config::ColorInput::Texture { filename, .. } => {
+                self.file(field(path, "filename"), filename)
+            }
+            config::ColorInput::Checker { even, odd, scale } => {
+                self.color(field(path, "even"), *even, albedo);
+                self.color(field(path, "odd"), *odd, albedo);
+                self.positive(field(path, "scale"), *scale);
+            }
+            config::ColorInput::Noise {
+                low,
+                high,
+                scale,
+                octaves,
+            } => {
+                self.color(field(path, "low"), *low, albedo);
+                self.color(field(path, "high"), *high, albedo);
+                self.positive(field(path, "scale"), *scale);
+                if *octaves == 0 {
+                    self.warning(field(path, "octaves"), "gives a constant color");
+                }
+            }
+        }
+    }
+
+    fn material(&mut self, path: &str, material: &config::Material) {
+        match material {
+            config::Material::Holdout { albedo } | config::Material::Lambert { albedo } => {
+                self.color_input(&field(path, "albedo"), albedo, true)
+            }
+            config::Material::Metal { albedo, fuzz } => {
+                self.color_input(&field(path, "albedo"), albedo, true);
+                self.non_negative(field(path, "fuzz"), *fuzz);
+                if *fuzz > 1.0 {
+                    self.warning(
+                        field(path, "fuzz"),
+                        "above 1 scatters many rays below the surface",
+                    );
+                }
+            }
+            config::Material::Dielectric {
+                albedo,
+                ior,
+                roughness,
+            } => {
+                self.color_input(&field(path, "albedo"), albedo, true);
+                self.ior(field(path, "ior"), ior);
+                self.unit(field(path, "roughness"), *roughness);
+            }
+            config::Material::Emissive {
+                color,
+                strength,
+                base,
+            } => {
+                self.color_input(&field(path, "color"), color, false);
+                self.non_negative(field(path, "strength"), *strength);
+                if let Some(base) = base {
+                    self.material(&field(path, "base"), base);
+                }
+            }
+            config::Material::Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+            } => {
+                self.color_input(&field(path, "base_color"), base_color, true);
+                self.unit(field(path, "metallic"), *metallic);
+                self.unit(field(path, "roughness"), *roughness);
+                self.unit(field(path, "specular"), *specular);
+            }
+            config::Material::Volume {
+                absorption,
+                scattering,
+                anisotropy,
+            } => self.medium(path, *absorption, *scattering, *anisotropy),
+            config::Material::Mix { a, b, factor } => {
+                self.material(&field(path, "a"), a);
+                self.material(&field(path, "b"), b);
+                match factor {
+                    config::Factor::Constant(factor) => self.unit(field(path, "factor"), *factor),
+                    config::Factor::Texture(texture) => {
+                        self.color_input(&field(path, "factor"), texture, true)
+                    }
+                }
+            }
+            config::Material::Coated {
+                base,
+                coat_ior,
+                coat_roughness,
+            } => {
+                self.material(&field(path, "base"), base);
+                self.positive(field(path, "coat_ior"), *coat_ior);
+                self.unit(field(path, "coat_roughness"), *coat_roughness);
+            }
+        }
+    }
+
+    /// Indices of refraction must be positive at least over the visible range, where
+    /// spectral rendering samples them.
+    fn ior(&mut self, path: String, ior: &config::Ior) {
+        if let config::Ior::Constant(ior) = ior {
+            return self.positive(path, *ior);
+        }
+        let steps = 40;
+        let invalid = (0..=steps)
+            .map(|i| {
+                let t = f64::from(i) / f64::from(steps);
+                MIN_WAVELENGTH + t * (MAX_WAVELENGTH - MIN_WAVELENGTH)
+            })
+            .find(|&wavelength| !(ior.at(wavelength) > 0.0));
+        if let Some(wavelength) = invalid {
+            self.error(
+                path,
+                format!(
+                    "must be positive, got {} at {} nm",
+                    ior.at(wavelength),
+                    wavelength
+                ),
+            );
+        }
+    }
+
+    fn medium(&mut self, path: &str, absorption: [f64; 3], scattering: [f64; 3], anisotropy: f64) {
+        self.color(field(path, "absorption"), absorption, false);
+        self.color(field(path, "scattering"), scattering, false);
+        if !(anisotropy.abs() < 1.0) {
+            self.error(
+                field(path, "anisotropy"),
+                format!("must be between -1 and 1, got {}", anisotropy),
+            );
+        }
+    }
+
+    /// Transforms must be invertible by the same rule as when objects are built.
+    fn transform(&mut self, path: &str, transform: &Option<config::Transform>) {
+        let path = field(path, "transform");
+        match transform {
+            Some(config::Transform::Matrix { matrix })
+                if objects::invert(Matrix4::from(*matrix).transpose()).is_none() =>
+            {
+                self.error(field(&path, "matrix"), "is not invertible");
+            }
+            None | Some(config::Transform::Matrix { .. }) => {}
+            Some(config::Transform::Components { rotate, scale, .. }) => {
+                if let config::Rotation::AxisAngle { axis, .. } = rotate {
+                    self.direction(field(&field(&path, "rotate"), "axis"), *axis);
+                }
+                if objects::invert(objects::scale_matrix(*scale)).is_none() {
+                    self.error(field(&path, "scale"), "makes the transform not invertible");
+                }
+            }
+        }
+    }
+
+    fn sdf(&mut self, path: &str, sdf: &config::SDF) {
+        match sdf {
+            config::SDF::Sphere { radius } => self.positive(field(path, "radius"), *radius),
+            config::SDF::Plane { normal } => self.direction(field(path, "normal"), *normal),
+            config::SDF::Box { size } => {
+                for (i, s) in size.iter().enumerate() {
+                    self.non_negative(index(&field(path, "size"), i), *s);
+                }
+            }
+            config::SDF::Rounding { sdf, .. } => self.sdf(&field(path, "sdf"), sdf),
+            config::SDF::Union {
+                left,
+                right,
+                smooth,
+            }
+            | config::SDF::Intersection {
+                left,
+                right,
+                smooth,
+            } => {
+                self.non_negative(field(path, "smooth"), *smooth);
+                self.sdf(&field(path, "left"), &left.value);
+                self.sdf(&field(path, "right"), &right.value);
+            }
+        }
+    }
+
+    fn object(&mut self, path: &str, object: &config::Object) {
+        match object {
+            config::Object::Sphere {
+                transform,
+                radius,
+                material,
+                ..
+            } => {
+                self.transform(path, transform);
+                self.positive(field(path, "radius"), *radius);
+                self.material(&field(path, "material"), material);
+            }
+            config::Object::Plane {
+                transform,
+                normal,
+                material,
+                ..
+            } => {
+                self.transform(path, transform);
+                self.direction(field(path, "normal"), *normal);
+                self.material(&field(path, "material"), material);
+            }
+            config::Object::SDF {
+                transform,
+                material,
+                sdf,
+                ..
+            } => {
+                self.transform(path, transform);
+                self.material(&field(path, "material"), material);
+                self.sdf(&field(path, "sdf"), sdf);
+            }
+            config::Object::Mesh {
+                transform,
+                filename,
+                material,
+                ..
+            } => {
+                self.transform(path, transform);
+                self.file(field(path, "filename"), filename);
+                self.material(&field(path, "material"), material);
+            }
+            config::Object::Instance {
+                transform,
+                of,
+                material_override,
+                ..
+            } => {
+                self.transform(path, transform);
+                if !self.definitions.contains_key(of) {
+                    self.error(field(path, "of"), format!("unknown object `{}`", of));
+                } else if self.instances_itself(of) {
+                    self.error(
+                        field(path, "of"),
+                        format!("object `{}` contains an instance of itself", of),
+                    );
+                }
+                if let Some(material) = material_override {
+                    self.material(&field(path, "material_override"), material);
+                }
+            }
+            config::Object::Group {
+                transform,
+                children,
+                ..
+            } => {
+                self.transform(path, transform);
+                if children.is_empty() {
+                    self.warning(field(path, "children"), "is empty");
+                }
+                for (i, child) in children.iter().enumerate() {
+                    self.object(&index(&field(path, "children"), i), child);
+                }
+            }
+        }
+    }
+
+    /// Whether the named object `name` contains an instance of itself, directly or not.
+    fn instances_itself(&self, name: &str) -> bool {
+        fn visit(
+            definitions: &BTreeMap<String, config::Object>,
+            object: &config::Object,
+            name: &str,
+            visited: &mut Vec<String>,
+        ) -> bool {
+            match object {
+                config::Object::Instance { of, .. } if of == name => true,
+                config::Object::Instance { of, .. } if !visited.contains(of) => {
+                    visited.push(of.clone());
+                    definitions
+                        .get(of)
+                        .is_some_and(|o| visit(definitions, o, name, visited))
+                }
+                config::Object::Group { children, .. } => children
+                    .iter()
+                    .any(|c| visit(definitions, c, name, visited)),
+                _ => false,
+            }
+        }
+        self.definitions
+            .get(name)
+            .is_some_and(|o| visit(self.definitions, o, name, &mut vec![]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(yaml: &str) -> Vec<(Severity, String)> {
+        let scene = serde_yaml::from_str(yaml).unwrap();
+        validate(&scene)
+            .into_iter()
+            .map(|p| (p.severity, p.path))
+            .collect()
+    }
+
+    #[test]
+    fn reports_paths() {
+        let found = problems(
+            "
+camera: {pos: [0, 0, 0], look_at: [0, 0, -1], up: [0, 1, 0]}
+objects:
+  a: {type: Group, children: [{type: Instance, of: b}]}
+  b: {type: Instance, of: a}
+world:
+  - {type: Sphere, radius: 1, material: {type: Lambert, albedo: {color: [0.5, 0.5, 0.5]}}}
+  - type: Group
+    children:
+      - {type: Sphere, radius: 1, material: {type: Dielectric, albedo: {color: [2, 1, 1]}, ior: 0}}
+  - {type: Instance, of: c, material_override: {type: Metal, albedo: {color: [1, 1, 1]}, fuzz: -1}}
+",
+        );
+        let expected = [
+            (Severity::Error, "objects.a.children[0].of"),
+            (Severity::Error, "objects.b.of"),
+            (
+                Severity::Warning,
+                "world[1].children[0].material.albedo.color",
+            ),
+            (Severity::Error, "world[1].children[0].material.ior"),
+            (Severity::Error, "world[2].of"),
+            (Severity::Error, "world[2].material_override.fuzz"),
+        ];
+        let expected: Vec<_> = expected.iter().map(|&(s, p)| (s, p.to_string())).collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn camera() {
+        let camera = |c: &str| problems(&format!("camera: {}\nworld: []", c));
+        assert_eq!(
+            camera("{pos: [0, 0, 0], look_at: [0, 5, 0], up: [0, 1, 0]}")[0],
+            (Severity::Error, "camera.up".to_string())
+        );
+        assert_eq!(
+            camera("{pos: [1, 1, 1], look_at: [1, 1, 1], up: [0, 1, 0], fov: 0}")[..2],
+            [
+                (Severity::Error, "camera.look_at".to_string()),
+                (Severity::Error, "camera.fov".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn media() {
+        let found = problems(
+            "
+camera: {pos: [0, 0, 0], look_at: [0, 0, -1], up: [0, 1, 0]}
+atmosphere: {scattering: [0.1, -0.1, 0.1], distance: 0}
+world:
+  - {type: Sphere, radius: 1, material: {type: Volume, scattering: [1, 1, 1], anisotropy: 1}}
+  - {type: Sphere, radius: 1, material: {type: Dielectric, albedo: {color: [1, 1, 1]}, ior: {a: 1.5, b: -1}}}
+",
+        );
+        let expected = [
+            (Severity::Error, "atmosphere.scattering"),
+            (Severity::Error, "atmosphere.distance"),
+            (Severity::Error, "world[0].material.anisotropy"),
+            (Severity::Error, "world[1].material.ior"),
+        ];
+        let expected: Vec<_> = expected.iter().map(|&(s, p)| (s, p.to_string())).collect();
+        assert_eq!(found, expected);
+    }
+
+    /// Both forms of transforms follow the same rule, whatever the size of the scale.
+    #[test]
+    fn transforms() {
+        let found = problems(
+            "
+camera: {pos: [0, 0, 0], look_at: [0, 0, -1], up: [0, 1, 0]}
+world:
+  - {type: Sphere, radius: 1, material: glass, transform: {scale: 1e-6}}
+  - type: Sphere
+    radius: 1
+    material: glass
+    transform: {matrix: [[1e-6, 0, 0, 0], [0, 1e-6, 0, 0], [0, 0, 1e-6, 0], [0, 0, 0, 1]]}
+  - {type: Sphere, radius: 1, material: glass, transform: {scale: [1, 0, 1]}}
+  - {type: Sphere, radius: 1, material: glass, transform: {scale: 1e-110}}
+  - type: Sphere
+    radius: 1
+    material: glass
+    transform: {matrix: [[1, 0, 0, 0], [1, 0, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]]}
+"
+            .replace("glass", "{type: Lambert, albedo: {color: [0.5, 0.5, 0.5]}}")
+            .as_str(),
+        );
+        let expected = [
+            (Severity::Error, "world[2].transform.scale"),
+            (Severity::Error, "world[3].transform.scale"),
+            (Severity::Error, "world[4].transform.matrix"),
+        ];
+        let expected: Vec<_> = expected.iter().map(|&(s, p)| (s, p.to_string())).collect();
+        assert_eq!(found, expected);
+    }
+}